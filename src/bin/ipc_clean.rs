@@ -0,0 +1,334 @@
+//! List POSIX shared memory objects and System V shared memory segments,
+//! and remove the ones left behind by processes that crashed.
+//!
+//! Attach counts of POSIX objects are gathered from `/proc/*/maps`, so
+//! mappings of processes that cannot be inspected are not counted.
+//!
+//! Linux only, because System V segments are found by walking the kernel's
+//! table with the Linux specific `IPC_INFO` and `SHM_STAT`.
+
+#[cfg(not(target_os = "linux"))]
+compile_error!("ipc-clean only supports Linux");
+
+extern crate interprocess;
+extern crate libc;
+
+use interprocess::shared_memory;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::process;
+use std::ptr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "\
+usage: ipc-clean [--shm-only | --xsi-only] [--prefix PREFIX] [--older-than AGE]
+                 [--unattached] [--remove [--dry-run]] [NAME | SHMID | KEY]...
+
+Lists the matching objects, or removes them with --remove. AGE is a number
+of seconds, or of minutes, hours or days with an m, h or d suffix.";
+
+/// Not exported by libc. Takes an index into the kernel's table of
+/// segments instead of a shmid, and returns the shmid.
+const SHM_STAT: libc::c_int = 13;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    /// A name under `/dev/shm`, without the leading slash.
+    Shm(String),
+    Xsi { shmid: i32, key: libc::key_t },
+}
+
+struct Object {
+    kind: Kind,
+    uid: u32,
+    size: u64,
+    attached: u64,
+    /// Seconds since the object was created or its permissions last changed.
+    age: u64,
+}
+
+impl Object {
+    fn name(&self) -> String {
+        match self.kind {
+            Kind::Shm(ref name) => format!("/{}", name),
+            Kind::Xsi { key, .. } => format!("0x{:08x}", key),
+        }
+    }
+
+    /// Identifies the object in messages. Keys are not unique, for one
+    /// because all private segments have key 0.
+    fn label(&self) -> String {
+        match self.kind {
+            Kind::Shm(ref name) => format!("shm /{}", name),
+            Kind::Xsi { shmid, .. } => format!("xsi {} ({})", shmid, self.name()),
+        }
+    }
+
+    fn remove(&self) -> io::Result<()> {
+        match self.kind {
+            Kind::Shm(ref name) => {
+                if shared_memory(format!("/{}", name)).remove() {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            },
+            Kind::Xsi { shmid, .. } => {
+                match unsafe { libc::shmctl(shmid, libc::IPC_RMID, ptr::null_mut()) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                }
+            },
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// Number of processes mapping each object under `/dev/shm`.
+fn shm_attach_counts() -> HashMap<String, u64> {
+    let mut counts = HashMap::new();
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return counts,
+    };
+    for entry in procs.filter_map(|entry| entry.ok()) {
+        if !entry.file_name().to_string_lossy().bytes().all(|c| c.is_ascii_digit()) {
+            continue
+        }
+        let maps = match fs::read_to_string(entry.path().join("maps")) {
+            Ok(maps) => maps,
+            Err(_) => continue,
+        };
+        let names: HashSet<_> = maps.lines()
+            .filter_map(|line| line.find("/dev/shm/").map(|i| &line[i + "/dev/shm/".len()..]))
+            .map(|name| name.trim_end_matches(" (deleted)"))
+            .collect();
+        for name in names {
+            *counts.entry(name.to_string()).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+fn shm_objects() -> io::Result<Vec<Object>> {
+    let attached = shm_attach_counts();
+    let now = now();
+    let mut objects = Vec::new();
+    for entry in fs::read_dir("/dev/shm")? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if !meta.is_file() {
+            continue
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        objects.push(Object {
+            uid: meta.uid(),
+            size: meta.size(),
+            attached: attached.get(&name).cloned().unwrap_or(0),
+            age: now.saturating_sub(meta.ctime() as u64),
+            kind: Kind::Shm(name),
+        });
+    }
+    Ok(objects)
+}
+
+/// Segments readable by this user, found by walking the kernel's table.
+fn xsi_objects() -> io::Result<Vec<Object>> {
+    let now = now();
+    let mut ds: libc::shmid_ds = unsafe { mem::zeroed() };
+    // IPC_INFO fills a struct shminfo, which is smaller than shmid_ds.
+    let max_index = match unsafe { libc::shmctl(0, libc::IPC_INFO, &mut ds) } {
+        -1 => return Err(io::Error::last_os_error()),
+        max_index => max_index,
+    };
+    let mut objects = Vec::new();
+    for index in 0..max_index + 1 {
+        let shmid = unsafe { libc::shmctl(index, SHM_STAT, &mut ds) };
+        if shmid == -1 {
+            continue
+        }
+        objects.push(Object {
+            kind: Kind::Xsi { shmid: shmid, key: ds.shm_perm.__key },
+            uid: ds.shm_perm.uid,
+            size: ds.shm_segsz as u64,
+            attached: ds.shm_nattch as u64,
+            age: now.saturating_sub(ds.shm_ctime as u64),
+        });
+    }
+    Ok(objects)
+}
+
+fn user_name(uid: u32) -> String {
+    unsafe {
+        let pw = libc::getpwuid(uid);
+        if pw.is_null() {
+            uid.to_string()
+        } else {
+            CStr::from_ptr((*pw).pw_name).to_string_lossy().into_owned()
+        }
+    }
+}
+
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..=119 => format!("{}s", secs),
+        120..=7199 => format!("{}m", secs / 60),
+        7200..=172_799 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+fn parse_age(age: &str) -> Option<u64> {
+    let (number, unit) = match age.chars().last() {
+        Some('s') => (&age[..age.len() - 1], 1),
+        Some('m') => (&age[..age.len() - 1], 60),
+        Some('h') => (&age[..age.len() - 1], 3600),
+        Some('d') => (&age[..age.len() - 1], 86400),
+        _ => (age, 1),
+    };
+    number.parse::<u64>().ok().and_then(|number| number.checked_mul(unit))
+}
+
+/// A key in hex, as `ipcs` prints it, with or without leading zeros.
+fn parse_key(key: &str) -> Option<libc::key_t> {
+    let digits = key.strip_prefix("0x").or_else(|| key.strip_prefix("0X"))?;
+    u32::from_str_radix(digits, 16).ok().map(|key| key as libc::key_t)
+}
+
+/// Which objects to list or remove. Every given criterion must match.
+#[derive(Default)]
+struct Filter {
+    no_shm: bool,
+    no_xsi: bool,
+    prefix: Option<String>,
+    older_than: Option<u64>,
+    unattached: bool,
+    /// Shm names, shmids or keys in hex.
+    names: Vec<String>,
+}
+
+impl Filter {
+    fn selects_any(&self) -> bool {
+        self.prefix.is_some() || self.older_than.is_some() || self.unattached || !self.names.is_empty()
+    }
+
+    fn matches(&self, object: &Object) -> bool {
+        let name_matches = match object.kind {
+            Kind::Shm(ref name) => {
+                !self.no_shm
+                    && self.prefix.as_ref().is_none_or(|prefix| name.starts_with(prefix.trim_start_matches('/')))
+                    && (self.names.is_empty()
+                        || self.names.iter().any(|arg| arg.trim_start_matches('/') == name))
+            },
+            Kind::Xsi { shmid, key } => {
+                !self.no_xsi
+                    && self.prefix.is_none()
+                    && (self.names.is_empty()
+                        || self.names.iter().any(|arg| match parse_key(arg) {
+                            Some(arg_key) => arg_key == key,
+                            None => arg.parse::<i32>() == Ok(shmid),
+                        }))
+            },
+        };
+        name_matches
+            && self.older_than.is_none_or(|age| object.age >= age)
+            && (!self.unattached || object.attached == 0)
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn main() {
+    let mut filter = Filter::default();
+    let mut remove = false;
+    let mut dry_run = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--shm-only" => filter.no_xsi = true,
+            "--xsi-only" => filter.no_shm = true,
+            "--prefix" => filter.prefix = Some(args.next().unwrap_or_else(|| usage())),
+            "--older-than" => {
+                let age = args.next().and_then(|age| parse_age(&age)).unwrap_or_else(|| usage());
+                filter.older_than = Some(age)
+            },
+            "--unattached" => filter.unattached = true,
+            "--remove" => remove = true,
+            "--dry-run" => dry_run = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return
+            },
+            _ if arg.starts_with('-') => usage(),
+            _ => filter.names.push(arg),
+        }
+    }
+    if dry_run && !remove {
+        usage()
+    }
+    if remove && !filter.selects_any() {
+        eprintln!("ipc-clean: refusing to remove everything, select objects by name, \
+                   --prefix, --older-than or --unattached");
+        process::exit(2)
+    }
+
+    let mut objects = Vec::new();
+    let mut failed = false;
+    for &(skip, list, what) in [(filter.no_shm, shm_objects as fn() -> io::Result<Vec<Object>>, "/dev/shm"),
+                                (filter.no_xsi, xsi_objects, "System V shared memory")].iter() {
+        if skip {
+            continue
+        }
+        match list() {
+            Ok(list) => objects.extend(list),
+            Err(err) => {
+                eprintln!("ipc-clean: cannot list {}: {}", what, err);
+                failed = true;
+            },
+        }
+    }
+    objects.retain(|object| filter.matches(object));
+    objects.sort_by(|a, b| a.kind.cmp(&b.kind));
+
+    if !remove {
+        println!("{:<4} {:>10} {:<12} {:>12} {:>8} {:>6}  NAME", "TYPE", "SHMID", "OWNER", "SIZE", "ATTACHED", "AGE");
+        for object in &objects {
+            let (kind, shmid) = match object.kind {
+                Kind::Shm(_) => ("shm", "-".to_string()),
+                Kind::Xsi { shmid, .. } => ("xsi", shmid.to_string()),
+            };
+            println!("{:<4} {:>10} {:<12} {:>12} {:>8} {:>6}  {}",
+                     kind, shmid, user_name(object.uid), object.size, object.attached,
+                     format_age(object.age), object.name());
+        }
+    } else {
+        for object in &objects {
+            if dry_run {
+                println!("would remove {}", object.label());
+                continue
+            }
+            match object.remove() {
+                Ok(()) => println!("removed {}", object.label()),
+                Err(err) => {
+                    eprintln!("ipc-clean: cannot remove {}: {}", object.label(), err);
+                    failed = true;
+                },
+            }
+        }
+    }
+
+    if failed {
+        process::exit(1)
+    }
+}
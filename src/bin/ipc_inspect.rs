@@ -0,0 +1,295 @@
+//! Print the header, memory statistics, objects and free blocks of a segment.
+//!
+//! The segment is mapped read-only and not locked, so it can be inspected
+//! while other processes use it, or after one of them died holding its lock.
+
+extern crate interprocess;
+
+use interprocess::{SegmentManager, SharedType, MappedRegion, HeaderError, HeaderInfo, ObjectInfo,
+                   SegmentProblem, XsiKey, shared_memory, file_mapping, xsi_shared_memory};
+use interprocess::mem_algo::{MemAlgo, MemStats, SimpleSeqFit, RbtreeBestFit};
+use interprocess::indexes::{Index, FlatMapIndex, RbtreeIndex, HashIndex};
+use interprocess::sync::{Mutex, NullMutex, SharedMutex, PrivateMutex, SharedRecursiveMutex,
+                        SharedUpgradableMutex};
+use std::env;
+use std::io;
+use std::process;
+
+const USAGE: &str = "usage: ipc-inspect [--json] (--shm NAME | --file PATH | --xsi KEY)";
+
+enum Source {
+    Shm(String),
+    File(String),
+    Xsi(i32),
+}
+
+impl Source {
+    fn open(&self) -> io::Result<MappedRegion> {
+        match *self {
+            Source::Shm(ref name) => shared_memory(name).read_only().open(),
+            Source::File(ref path) => file_mapping(path).read_only().open(),
+            Source::Xsi(key) => xsi_shared_memory(XsiKey::from_raw(key)).read_only().open(),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match *self {
+            Source::Shm(_) => "shm",
+            Source::File(_) => "file",
+            Source::Xsi(_) => "xsi",
+        }
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            Source::Shm(ref name) | Source::File(ref name) => name.clone(),
+            Source::Xsi(key) => format!("0x{:08x}", key),
+        }
+    }
+}
+
+/// Everything gathered from a segment. The statistics and lists are `None`
+/// when the segment is too damaged to walk them safely.
+struct Report {
+    algorithm: String,
+    index: String,
+    header: HeaderInfo,
+    stats: Option<MemStats>,
+    named: Option<Vec<ObjectInfo>>,
+    unique: Option<Vec<ObjectInfo>>,
+    free_blocks: Option<Vec<(usize, usize)>>,
+    problems: Vec<SegmentProblem>,
+}
+
+type Inspect = fn(&Source) -> io::Result<Option<Report>>;
+
+/// The segment in `source`, or `None` if it was created with other types.
+fn inspect<A: MemAlgo, I: Index>(source: &Source) -> io::Result<Option<Report>> {
+    let segment = match SegmentManager::<A, I>::open_read_only(source.open()?) {
+        Ok(segment) => segment,
+        Err(ref err) if is_layout(err) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let problems = segment.check_integrity();
+    let blocks_ok = !problems.iter().any(|problem| matches!(*problem, SegmentProblem::Memory(_)));
+    let (stats, free_blocks) = if blocks_ok {
+        let mut blocks = Vec::new();
+        segment.free_blocks(|offset, size| blocks.push((offset, size)));
+        blocks.sort();
+        (Some(segment.stats()), Some(blocks))
+    } else {
+        (None, None)
+    };
+    let objects = |iter: interprocess::ObjectIter| {
+        let mut objects: Vec<_> = iter.collect();
+        objects.sort_by_key(|object| object.offset);
+        objects
+    };
+    let (named, unique) = if problems.is_empty() {
+        (Some(objects(segment.named_iter())), Some(objects(segment.unique_iter())))
+    } else {
+        (None, None)
+    };
+
+    Ok(Some(Report {
+        algorithm: format!("{}<{}>", A::NAME, A::Mutex::NAME),
+        index: I::NAME.to_string(),
+        header: segment.header_info(),
+        stats: stats,
+        named: named,
+        unique: unique,
+        free_blocks: free_blocks,
+        problems: problems,
+    }))
+}
+
+fn is_layout(err: &io::Error) -> bool {
+    matches!(HeaderError::from_io(err), Some(HeaderError::Layout { .. }))
+}
+
+/// The memory algorithms and indexes of this crate, over `M`.
+fn layouts<M: Mutex + 'static>() -> Vec<Inspect> {
+    vec![inspect::<RbtreeBestFit<M>, RbtreeIndex>,
+         inspect::<RbtreeBestFit<M>, FlatMapIndex>,
+         inspect::<RbtreeBestFit<M>, HashIndex>,
+         inspect::<SimpleSeqFit<M>, RbtreeIndex>,
+         inspect::<SimpleSeqFit<M>, FlatMapIndex>,
+         inspect::<SimpleSeqFit<M>, HashIndex>]
+}
+
+fn inspect_any(source: &Source) -> io::Result<Report> {
+    let layouts = layouts::<SharedMutex>().into_iter()
+        .chain(layouts::<NullMutex>())
+        .chain(layouts::<PrivateMutex>())
+        .chain(layouts::<SharedRecursiveMutex>())
+        .chain(layouts::<SharedUpgradableMutex>());
+    for inspect in layouts {
+        if let Some(report) = inspect(source)? {
+            return Ok(report)
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData,
+                       "segment was created with a memory algorithm or index unknown to ipc-inspect"))
+}
+
+fn print_text(source: &Source, report: &Report) {
+    let header = &report.header;
+    println!("segment        {} ({})", source.name(), source.kind());
+    println!("format         version {}, {}-bit, {} endian",
+             header.version, header.pointer_width, if header.little_endian { "little" } else { "big" });
+    println!("layout         {:016x} ({}, {})", header.layout, report.algorithm, report.index);
+    match report.stats {
+        Some(ref stats) => {
+            println!("size           {}", stats.size);
+            println!("free memory    {} in {} blocks, largest {}",
+                     stats.free_memory, stats.free_blocks, stats.largest_free_block);
+            println!("fragmentation  {:.3}", stats.fragmentation());
+        }
+        None => println!("free memory    (not counted, the segment is damaged)"),
+    }
+
+    for &(title, objects) in [("named objects", &report.named), ("unique objects", &report.unique)].iter() {
+        println!();
+        match *objects {
+            Some(ref objects) => {
+                println!("{} ({})", title, objects.len());
+                for object in objects {
+                    println!("  {:>10}  {:>6} x {:<6}  {:016x}  {}",
+                             object.offset, object.count, object.value_size, object.fingerprint, object.name);
+                }
+            },
+            None => println!("{} (not listed, the segment is damaged)", title),
+        }
+    }
+
+    println!();
+    match report.free_blocks {
+        Some(ref blocks) => {
+            println!("free blocks ({})", blocks.len());
+            for &(offset, size) in blocks {
+                println!("  {:>10}  {}", offset, size);
+            }
+        },
+        None => println!("free blocks (not listed, the segment is damaged)"),
+    }
+
+    println!();
+    if report.problems.is_empty() {
+        println!("no problems found");
+    } else {
+        println!("problems ({})", report.problems.len());
+        for problem in &report.problems {
+            println!("  {}", problem);
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_list<T, F>(items: &Option<Vec<T>>, func: F) -> String
+    where F: Fn(&T) -> String
+{
+    match *items {
+        Some(ref items) => format!("[{}]", items.iter().map(func).collect::<Vec<_>>().join(",")),
+        None => "null".to_string(),
+    }
+}
+
+fn json_object(object: &ObjectInfo) -> String {
+    format!("{{\"name\":{},\"offset\":{},\"count\":{},\"value_size\":{},\"fingerprint\":\"{:016x}\"}}",
+            json_string(&object.name), object.offset, object.count, object.value_size, object.fingerprint)
+}
+
+fn print_json(source: &Source, report: &Report) {
+    let header = &report.header;
+    let stats = match report.stats {
+        Some(ref stats) => format!("{{\"size\":{},\"free_memory\":{},\"free_blocks\":{},\"largest_free_block\":{},\
+                                    \"fragmentation\":{}}}",
+                                   stats.size, stats.free_memory, stats.free_blocks, stats.largest_free_block,
+                                   stats.fragmentation()),
+        None => "null".to_string(),
+    };
+    let problems: Vec<_> = report.problems.iter().map(|problem| json_string(&problem.to_string())).collect();
+    println!("{{\"source\":{{\"kind\":\"{}\",\"name\":{}}},\
+              \"header\":{{\"version\":{},\"pointer_width\":{},\"endian\":\"{}\",\"layout\":\"{:016x}\",\
+              \"algorithm\":{},\"index\":{}}},\
+              \"stats\":{},\
+              \"named\":{},\"unique\":{},\"free_blocks\":{},\"problems\":[{}]}}",
+             source.kind(), json_string(&source.name()),
+             header.version, header.pointer_width, if header.little_endian { "little" } else { "big" },
+             header.layout, json_string(&report.algorithm), json_string(&report.index),
+             stats,
+             json_list(&report.named, json_object),
+             json_list(&report.unique, json_object),
+             json_list(&report.free_blocks, |&(offset, size)| format!("{{\"offset\":{},\"size\":{}}}", offset, size)),
+             problems.join(","));
+}
+
+/// Keys are listed by `ipcs` in hex, but may be given in decimal too.
+fn parse_key(key: &str) -> Option<i32> {
+    if key.starts_with("0x") || key.starts_with("0X") {
+        u32::from_str_radix(&key[2..], 16).ok().map(|key| key as i32)
+    } else {
+        key.parse().ok()
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn main() {
+    let mut json = false;
+    let mut source = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--shm" => source = Some(Source::Shm(args.next().unwrap_or_else(|| usage()))),
+            "--file" => source = Some(Source::File(args.next().unwrap_or_else(|| usage()))),
+            "--xsi" => {
+                let key = args.next().and_then(|key| parse_key(&key)).unwrap_or_else(|| usage());
+                source = Some(Source::Xsi(key))
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return
+            },
+            _ => usage(),
+        }
+    }
+    let source = source.unwrap_or_else(|| usage());
+
+    match inspect_any(&source) {
+        Ok(report) => {
+            if json {
+                print_json(&source, &report);
+            } else {
+                print_text(&source, &report);
+            }
+            if !report.problems.is_empty() {
+                process::exit(1)
+            }
+        },
+        Err(err) => {
+            eprintln!("ipc-inspect: {}: {}", source.name(), err);
+            process::exit(1)
+        },
+    }
+}
@@ -10,6 +10,11 @@ impl ErrCode {
     pub fn last_error() -> Self {
         ErrCode(unsafe { *libc::__errno_location() })
     }
+
+    /// An error number returned directly, as the pthread functions do.
+    pub fn new(code: i32) -> Self {
+        ErrCode(code)
+    }
 }
 
 impl fmt::Debug for ErrCode {
@@ -30,3 +35,4 @@ pub const FILE_EXISTS: ErrCode = ErrCode(libc::EEXIST);
 pub const PERMISSION_DENIED: ErrCode = ErrCode(libc::EACCES);
 pub const NO_SUCH_FILE_OR_DIRECTORY: ErrCode = ErrCode(libc::ENOENT);
 pub const INVALID_ARGUMENT: ErrCode = ErrCode(libc::EINVAL);
+pub const STATE_NOT_RECOVERABLE: ErrCode = ErrCode(libc::ENOTRECOVERABLE);
@@ -0,0 +1,152 @@
+use indexes::Index;
+use mem_algo::MemAlgo;
+use ptr::OffsetPtr;
+use SharedType;
+use std::ptr;
+use std::slice;
+
+/// Entries hold offsets from the index, so they stay valid while the array
+/// is shifted or reallocated.
+#[repr(C)]
+struct Entry {
+    key: isize,
+    key_len: usize,
+    value: isize,
+}
+
+/// Sorted array of entries searched with binary search.
+#[repr(C)]
+pub struct FlatMapIndex {
+    entries: OffsetPtr<Entry>,
+    len: usize,
+    capacity: usize,
+}
+
+impl FlatMapIndex {
+    fn base(&self) -> isize {
+        self as *const Self as isize
+    }
+
+    fn entries(&self) -> &[Entry] {
+        if self.len == 0 {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.entries.get(), self.len) }
+        }
+    }
+
+    fn value(&self, entry: &Entry) -> *mut u8 {
+        (self.base() + entry.value) as *mut u8
+    }
+
+    fn key(&self, entry: &Entry) -> &[u8] {
+        unsafe { slice::from_raw_parts((self.base() + entry.key) as *const u8, entry.key_len) }
+    }
+
+    fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        self.entries().binary_search_by(|entry| self.key(entry).cmp(key))
+    }
+
+    fn reserve<A: MemAlgo>(&mut self, algo: &mut A) -> bool {
+        if self.len < self.capacity {
+            return true
+        }
+        let capacity = if self.capacity == 0 { 8 } else { self.capacity * 2 };
+        let entries = algo.realloc(self.entries.get(), capacity);
+        if entries.is_null() {
+            return false
+        }
+        self.entries.set(entries);
+        self.capacity = capacity;
+        true
+    }
+}
+
+unsafe impl SharedType for FlatMapIndex {
+    const NAME: &'static str = "FlatMapIndex";
+}
+
+impl Index for FlatMapIndex {
+    type Hook = ();
+
+    type Iter<'a> = FlatMapIter<'a>;
+
+    fn place_new(&mut self) {
+        self.entries = OffsetPtr::null();
+        self.len = 0;
+        self.capacity = 0;
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn find(&self, key: &[u8]) -> Option<*mut u8> {
+        self.search(key)
+            .ok()
+            .map(|i| self.value(&self.entries()[i]))
+    }
+
+    unsafe fn insert<A: MemAlgo>(&mut self, algo: &mut A, _hook: *mut (), key: &[u8], value: *mut u8) -> bool {
+        let pos = match self.search(key) {
+            Ok(_) => return false,
+            Err(pos) => pos,
+        };
+        if !self.reserve(algo) {
+            return false
+        }
+
+        let entry = Entry {
+            key: key.as_ptr() as isize - self.base(),
+            key_len: key.len(),
+            value: value as isize - self.base(),
+        };
+        unsafe {
+            let at = self.entries.get().add(pos);
+            ptr::copy(at, at.add(1), self.len - pos);
+            ptr::write(at, entry);
+        }
+        self.len += 1;
+        true
+    }
+
+    fn remove<A: MemAlgo>(&mut self, algo: &mut A, key: &[u8]) -> Option<*mut u8> {
+        let pos = self.search(key).ok()?;
+        let value = self.value(&self.entries()[pos]);
+        unsafe {
+            let at = self.entries.get().add(pos);
+            ptr::copy(at.add(1), at, self.len - pos - 1);
+        }
+        self.len -= 1;
+
+        if self.len == 0 {
+            algo.dealloc(self.entries.get(), self.capacity);
+            self.entries = OffsetPtr::null();
+            self.capacity = 0;
+        }
+        Some(value)
+    }
+
+    fn iter(&self) -> FlatMapIter<'_> {
+        FlatMapIter {
+            index: self,
+            pos: 0,
+        }
+    }
+}
+
+/// Visits the entries of a `FlatMapIndex` in key order.
+pub struct FlatMapIter<'a> {
+    index: &'a FlatMapIndex,
+    pos: usize,
+}
+
+impl<'a> Iterator for FlatMapIter<'a> {
+    type Item = (&'a [u8], *mut u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.index.entries().get(self.pos)?;
+        self.pos += 1;
+        Some((self.index.key(entry), self.index.value(entry)))
+    }
+}
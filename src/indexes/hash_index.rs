@@ -0,0 +1,224 @@
+use indexes::Index;
+use mem_algo::MemAlgo;
+use ptr::OffsetPtr;
+use SharedType;
+use std::ptr;
+use std::slice;
+
+/// Slots hold offsets from the index, so they can be copied while rehashing.
+/// A `value` of 0 marks an empty slot, because no value lives at the index itself.
+#[repr(C)]
+struct Slot {
+    hash: u64,
+    key: isize,
+    key_len: usize,
+    value: isize,
+}
+
+impl Slot {
+    fn is_empty(&self) -> bool {
+        self.value == 0
+    }
+}
+
+/// FNV-1a, which unlike the std hashers gives the same result in every
+/// process and build that opens the segment.
+pub(crate) fn fnv1a(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+const MIN_CAPACITY: usize = 16;
+
+/// Open-addressing hash table with linear probing.
+#[repr(C)]
+pub struct HashIndex {
+    slots: OffsetPtr<Slot>,
+    len: usize,
+    capacity: usize,
+}
+
+impl HashIndex {
+    fn base(&self) -> isize {
+        self as *const Self as isize
+    }
+
+    fn slots(&self) -> &[Slot] {
+        if self.capacity == 0 {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.slots.get(), self.capacity) }
+        }
+    }
+
+    fn slots_mut(&mut self) -> &mut [Slot] {
+        if self.capacity == 0 {
+            &mut []
+        } else {
+            unsafe { slice::from_raw_parts_mut(self.slots.get(), self.capacity) }
+        }
+    }
+
+    fn key(&self, slot: &Slot) -> &[u8] {
+        unsafe { slice::from_raw_parts((self.base() + slot.key) as *const u8, slot.key_len) }
+    }
+
+    fn value(&self, slot: &Slot) -> *mut u8 {
+        (self.base() + slot.value) as *mut u8
+    }
+
+    fn position(&self, key: &[u8]) -> Option<usize> {
+        if self.capacity == 0 {
+            return None
+        }
+
+        let hash = fnv1a(key);
+        let mask = self.capacity - 1;
+        let slots = self.slots();
+        let mut i = hash as usize & mask;
+        while !slots[i].is_empty() {
+            if slots[i].hash == hash && self.key(&slots[i]) == key {
+                return Some(i)
+            }
+            i = (i + 1) & mask;
+        }
+        None
+    }
+
+    /// Put `slot` into the first free position of its probe sequence.
+    fn place(&mut self, slot: Slot) {
+        let mask = self.capacity - 1;
+        let slots = self.slots_mut();
+        let mut i = slot.hash as usize & mask;
+        while !slots[i].is_empty() {
+            i = (i + 1) & mask;
+        }
+        slots[i] = slot;
+    }
+
+    /// Keep the load factor under 3/4.
+    fn reserve<A: MemAlgo>(&mut self, algo: &mut A) -> bool {
+        if (self.len + 1) * 4 <= self.capacity * 3 {
+            return true
+        }
+
+        let capacity = if self.capacity == 0 { MIN_CAPACITY } else { self.capacity * 2 };
+        let slots = algo.alloc::<Slot>(capacity);
+        if slots.is_null() {
+            return false
+        }
+        unsafe { ptr::write_bytes(slots, 0, capacity) };
+
+        let old = self.slots.get();
+        let old_capacity = self.capacity;
+        self.slots.set(slots);
+        self.capacity = capacity;
+        for i in 0..old_capacity {
+            let slot = unsafe { ptr::read(old.add(i)) };
+            if !slot.is_empty() {
+                self.place(slot);
+            }
+        }
+        algo.dealloc(old, old_capacity);
+        true
+    }
+}
+
+unsafe impl SharedType for HashIndex {
+    const NAME: &'static str = "HashIndex";
+}
+
+impl Index for HashIndex {
+    type Hook = ();
+
+    type Iter<'a> = HashIter<'a>;
+
+    fn place_new(&mut self) {
+        self.slots = OffsetPtr::null();
+        self.len = 0;
+        self.capacity = 0;
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn find(&self, key: &[u8]) -> Option<*mut u8> {
+        self.position(key).map(|i| self.value(&self.slots()[i]))
+    }
+
+    unsafe fn insert<A: MemAlgo>(&mut self, algo: &mut A, _hook: *mut (), key: &[u8], value: *mut u8) -> bool {
+        if self.position(key).is_some() || !self.reserve(algo) {
+            return false
+        }
+
+        let slot = Slot {
+            hash: fnv1a(key),
+            key: key.as_ptr() as isize - self.base(),
+            key_len: key.len(),
+            value: value as isize - self.base(),
+        };
+        self.place(slot);
+        self.len += 1;
+        true
+    }
+
+    fn remove<A: MemAlgo>(&mut self, algo: &mut A, key: &[u8]) -> Option<*mut u8> {
+        let mut i = self.position(key)?;
+        let value = self.value(&self.slots()[i]);
+        let mask = self.capacity - 1;
+
+        // Shift later members of the probe run back, so lookups need no tombstones.
+        let slots = self.slots_mut();
+        let mut j = i;
+        loop {
+            j = (j + 1) & mask;
+            if slots[j].is_empty() {
+                break
+            }
+            let home = slots[j].hash as usize & mask;
+            let movable = if i <= j { home <= i || home > j } else { home <= i && home > j };
+            if movable {
+                slots.swap(i, j);
+                i = j;
+            }
+        }
+        slots[i].value = 0;
+        self.len -= 1;
+
+        if self.len == 0 {
+            algo.dealloc(self.slots.get(), self.capacity);
+            self.slots = OffsetPtr::null();
+            self.capacity = 0;
+        }
+        Some(value)
+    }
+
+    fn iter(&self) -> HashIter<'_> {
+        HashIter {
+            index: self,
+            pos: 0,
+        }
+    }
+}
+
+/// Visits the entries of a `HashIndex` in table order.
+pub struct HashIter<'a> {
+    index: &'a HashIndex,
+    pos: usize,
+}
+
+impl<'a> Iterator for HashIter<'a> {
+    type Item = (&'a [u8], *mut u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let slots = self.index.slots();
+        while self.pos < slots.len() {
+            let slot = &slots[self.pos];
+            self.pos += 1;
+            if !slot.is_empty() {
+                return Some((self.index.key(slot), self.index.value(slot)))
+            }
+        }
+        None
+    }
+}
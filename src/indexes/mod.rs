@@ -1,2 +1,104 @@
-pub trait Index {
+use mem_algo::MemAlgo;
+use SharedType;
+
+/// Maps object names to their headers inside a segment.
+///
+/// An index is placed in the segment itself, so it may only refer to
+/// memory through offsets and must take further memory from `algo`.
+pub trait Index: SharedType {
+    /// Storage the segment reserves for the index next to every value it links.
+    type Hook;
+
+    type Iter<'a>: Iterator<Item = (&'a [u8], *mut u8)> where Self: 'a;
+
+    fn place_new(&mut self);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn find(&self, key: &[u8]) -> Option<*mut u8>;
+
+    /// Returns `false` if `key` is already present or memory ran out.
+    ///
+    /// # Safety
+    ///
+    /// `key` and `hook` must stay valid inside the segment for as long as the entry.
+    unsafe fn insert<A: MemAlgo>(&mut self, algo: &mut A, hook: *mut Self::Hook, key: &[u8], value: *mut u8) -> bool;
+
+    /// Unlink `key` and return the value it mapped to.
+    fn remove<A: MemAlgo>(&mut self, algo: &mut A, key: &[u8]) -> Option<*mut u8>;
+
+    /// Visit every entry, in no particular order unless the index is sorted.
+    fn iter(&self) -> Self::Iter<'_>;
+}
+
+mod flat_map_index;
+pub use self::flat_map_index::*;
+
+mod rbtree_index;
+pub use self::rbtree_index::*;
+
+mod hash_index;
+pub use self::hash_index::*;
+
+#[test]
+fn test_indexes() {
+    use mem_algo::{SimpleSeqFit, xorshift};
+    use sync::NullMutex;
+    use std::collections::BTreeMap;
+    use std::mem;
+    use std::ptr;
+    use std::slice;
+
+    fn run<I: Index>() {
+        const SEGMENT_SIZE: usize = 1024 * 1024;
+        let mut buf = vec![0u64; SEGMENT_SIZE / 8];
+        let algo: &mut SimpleSeqFit<NullMutex> = unsafe { &mut *(buf.as_mut_ptr() as *mut _) };
+        algo.place_new(SEGMENT_SIZE);
+
+        let index: &mut I = unsafe { &mut *algo.alloc::<I>(1) };
+        index.place_new();
+
+        // Each entry owns a hook followed by its key, as in a segment block.
+        let entry = |algo: &mut SimpleSeqFit<NullMutex>, key: &str| unsafe {
+            let hook = algo.alloc::<u8>(mem::size_of::<I::Hook>() + key.len());
+            let name = hook.add(mem::size_of::<I::Hook>());
+            ptr::copy_nonoverlapping(key.as_ptr(), name, key.len());
+            (hook as *mut I::Hook, slice::from_raw_parts(name as *const u8, key.len()))
+        };
+
+        let mut expect = BTreeMap::new();
+        let mut rand = xorshift(0x2545f4914f6cdd1d);
+        for _ in 0..5000 {
+            let seed = rand();
+            let key = format!("key{}", seed % 500);
+            let value = ((seed % 500 + 1) * 16) as *mut u8;
+
+            if seed % 3 == 1 {
+                assert_eq!(index.remove(algo, key.as_bytes()), expect.remove(&key));
+            } else {
+                let (hook, name) = entry(algo, &key);
+                assert_eq!(unsafe { index.insert(algo, hook, name, value) }, !expect.contains_key(&key));
+                expect.entry(key).or_insert(value);
+            }
+            assert_eq!(index.len(), expect.len());
+        }
+
+        for (key, &value) in expect.iter() {
+            assert_eq!(index.find(key.as_bytes()), Some(value));
+        }
+        assert!(index.find(b"missing").is_none());
+        let mut found: Vec<_> = index.iter()
+            .map(|(key, value)| (String::from_utf8(key.to_vec()).unwrap(), value))
+            .collect();
+        found.sort();
+        assert_eq!(found, expect.into_iter().collect::<Vec<_>>());
+    }
+
+    run::<FlatMapIndex>();
+    run::<RbtreeIndex>();
+    run::<HashIndex>();
 }
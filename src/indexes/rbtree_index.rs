@@ -0,0 +1,123 @@
+use indexes::Index;
+use intrusive::rbtree::{self, Tree, Node};
+use mem_algo::MemAlgo;
+use ptr::OffsetPtr;
+use SharedType;
+use std::marker::PhantomData;
+use std::slice;
+
+/// Tree node kept inside the block of every value, so linking a value
+/// never allocates.
+#[repr(C)]
+pub struct RbtreeHook {
+    node: Node,
+    key: OffsetPtr<u8>,
+    key_len: usize,
+    value: OffsetPtr<u8>,
+}
+
+impl RbtreeHook {
+    fn from_node(node: &Node) -> &RbtreeHook {
+        unsafe { &*(node as *const Node as *const RbtreeHook) }
+    }
+
+    fn key(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.key.get(), self.key_len) }
+    }
+}
+
+fn less(a: &Node, b: &Node) -> bool {
+    RbtreeHook::from_node(a).key() < RbtreeHook::from_node(b).key()
+}
+
+/// Intrusive red-black tree ordered by key.
+#[repr(C)]
+pub struct RbtreeIndex {
+    tree: Tree,
+    len: usize,
+}
+
+impl RbtreeIndex {
+    fn find_node(&self, key: &[u8]) -> *mut Node {
+        unsafe { self.tree.find(|node| RbtreeHook::from_node(node).key().cmp(key)) }
+    }
+}
+
+unsafe impl SharedType for RbtreeIndex {
+    const NAME: &'static str = "RbtreeIndex";
+}
+
+impl Index for RbtreeIndex {
+    type Hook = RbtreeHook;
+
+    type Iter<'a> = RbtreeIter<'a>;
+
+    fn place_new(&mut self) {
+        self.tree.place_new();
+        self.len = 0;
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn find(&self, key: &[u8]) -> Option<*mut u8> {
+        let node = self.find_node(key);
+        if node.is_null() {
+            None
+        } else {
+            Some(RbtreeHook::from_node(unsafe { &*node }).value.get())
+        }
+    }
+
+    unsafe fn insert<A: MemAlgo>(&mut self, _algo: &mut A, hook: *mut RbtreeHook, key: &[u8], value: *mut u8) -> bool {
+        if !self.find_node(key).is_null() {
+            return false
+        }
+
+        let hook = &mut *hook;
+        hook.key.set(key.as_ptr());
+        hook.key_len = key.len();
+        hook.value.set(value);
+        self.tree.insert(&mut hook.node, less);
+        self.len += 1;
+        true
+    }
+
+    fn remove<A: MemAlgo>(&mut self, _algo: &mut A, key: &[u8]) -> Option<*mut u8> {
+        let node = self.find_node(key);
+        if node.is_null() {
+            return None
+        }
+
+        unsafe { self.tree.remove(node) };
+        self.len -= 1;
+        Some(RbtreeHook::from_node(unsafe { &*node }).value.get())
+    }
+
+    fn iter(&self) -> RbtreeIter<'_> {
+        RbtreeIter {
+            cur: self.tree.first(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Visits the entries of an `RbtreeIndex` in key order.
+pub struct RbtreeIter<'a> {
+    cur: *mut Node,
+    _marker: PhantomData<&'a RbtreeIndex>,
+}
+
+impl<'a> Iterator for RbtreeIter<'a> {
+    type Item = (&'a [u8], *mut u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cur.is_null() {
+            return None
+        }
+        let hook: &'a RbtreeHook = RbtreeHook::from_node(unsafe { &*self.cur });
+        self.cur = unsafe { rbtree::next(self.cur) };
+        Some((hook.key(), hook.value.get()))
+    }
+}
@@ -7,3 +7,5 @@ mod adapter;
 pub use self::adapter::{Adapter};
 
 pub mod slist;
+
+pub(crate) mod rbtree;
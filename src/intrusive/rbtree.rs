@@ -0,0 +1,405 @@
+//! Red-black tree algorithms over nodes linked by `OffsetPtr`.
+//!
+//! The nodes are embedded in the values, so a tree can live inside a mapped
+//! region and be shared between processes mapping it at different addresses.
+
+use ptr::OffsetPtr;
+use std::cmp::Ordering;
+use std::ptr;
+
+#[repr(C)]
+pub struct Node {
+    parent: OffsetPtr<Node>,
+    left: OffsetPtr<Node>,
+    right: OffsetPtr<Node>,
+    red: bool,
+}
+
+#[repr(C)]
+pub struct Tree {
+    root: OffsetPtr<Node>,
+}
+
+pub unsafe fn parent(node: *mut Node) -> *mut Node {
+    (*node).parent.get()
+}
+
+pub unsafe fn left(node: *mut Node) -> *mut Node {
+    (*node).left.get()
+}
+
+pub unsafe fn right(node: *mut Node) -> *mut Node {
+    (*node).right.get()
+}
+
+unsafe fn is_red(node: *mut Node) -> bool {
+    !node.is_null() && (*node).red
+}
+
+unsafe fn minimum(mut node: *mut Node) -> *mut Node {
+    while !left(node).is_null() {
+        node = left(node);
+    }
+    node
+}
+
+/// In-order successor of `node`, or null if it is the last one.
+pub unsafe fn next(mut node: *mut Node) -> *mut Node {
+    if !right(node).is_null() {
+        return minimum(right(node))
+    }
+    let mut p = parent(node);
+    while !p.is_null() && node == right(p) {
+        node = p;
+        p = parent(p);
+    }
+    p
+}
+
+impl Tree {
+    pub fn place_new(&mut self) {
+        self.root = OffsetPtr::null();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_null()
+    }
+
+    pub fn root(&self) -> *mut Node {
+        self.root.get()
+    }
+
+    /// Leftmost node, or null if the tree is empty.
+    pub fn first(&self) -> *mut Node {
+        let root = self.root.get();
+        if root.is_null() {
+            root
+        } else {
+            unsafe { minimum(root) }
+        }
+    }
+
+    /// Link `node` in, after any nodes that compare equal to it.
+    pub unsafe fn insert<F>(&mut self, node: *mut Node, less: F)
+        where F: Fn(&Node, &Node) -> bool
+    {
+        let mut p = ptr::null_mut();
+        let mut cur = self.root.get();
+        let mut go_left = false;
+        while !cur.is_null() {
+            p = cur;
+            go_left = less(&*node, &*cur);
+            cur = if go_left { left(cur) } else { right(cur) };
+        }
+
+        (*node).parent.set(p);
+        (*node).left.set(ptr::null());
+        (*node).right.set(ptr::null());
+        (*node).red = true;
+        if p.is_null() {
+            self.root.set(node);
+        } else if go_left {
+            (*p).left.set(node);
+        } else {
+            (*p).right.set(node);
+        }
+
+        self.insert_fixup(node);
+    }
+
+    /// Node for which `cmp` returns `Equal`, where `cmp` orders a node against the key.
+    pub unsafe fn find<F>(&self, cmp: F) -> *mut Node
+        where F: Fn(&Node) -> Ordering
+    {
+        let mut cur = self.root.get();
+        while !cur.is_null() {
+            cur = match cmp(&*cur) {
+                Ordering::Less => right(cur),
+                Ordering::Greater => left(cur),
+                Ordering::Equal => return cur,
+            };
+        }
+        cur
+    }
+
+    /// First node for which `is_less` is false, or null.
+    pub unsafe fn lower_bound<F>(&self, is_less: F) -> *mut Node
+        where F: Fn(&Node) -> bool
+    {
+        let mut found = ptr::null_mut();
+        let mut cur = self.root.get();
+        while !cur.is_null() {
+            if is_less(&*cur) {
+                cur = right(cur);
+            } else {
+                found = cur;
+                cur = left(cur);
+            }
+        }
+        found
+    }
+
+    pub unsafe fn remove(&mut self, z: *mut Node) {
+        let x;
+        let x_parent;
+        let mut removed_red = (*z).red;
+
+        if left(z).is_null() {
+            x = right(z);
+            x_parent = parent(z);
+            self.transplant(z, x);
+        } else if right(z).is_null() {
+            x = left(z);
+            x_parent = parent(z);
+            self.transplant(z, x);
+        } else {
+            let y = minimum(right(z));
+            removed_red = (*y).red;
+            x = right(y);
+            if parent(y) == z {
+                x_parent = y;
+            } else {
+                x_parent = parent(y);
+                self.transplant(y, x);
+                (*y).right.set(right(z));
+                (*right(y)).parent.set(y);
+            }
+            self.transplant(z, y);
+            (*y).left.set(left(z));
+            (*left(y)).parent.set(y);
+            (*y).red = (*z).red;
+        }
+
+        if !removed_red {
+            self.remove_fixup(x, x_parent);
+        }
+    }
+
+    unsafe fn transplant(&mut self, u: *mut Node, v: *mut Node) {
+        let p = parent(u);
+        if p.is_null() {
+            self.root.set(v);
+        } else if u == left(p) {
+            (*p).left.set(v);
+        } else {
+            (*p).right.set(v);
+        }
+        if !v.is_null() {
+            (*v).parent.set(p);
+        }
+    }
+
+    unsafe fn rotate_left(&mut self, x: *mut Node) {
+        let y = right(x);
+        (*x).right.set(left(y));
+        if !left(y).is_null() {
+            (*left(y)).parent.set(x);
+        }
+        self.transplant(x, y);
+        (*y).left.set(x);
+        (*x).parent.set(y);
+    }
+
+    unsafe fn rotate_right(&mut self, x: *mut Node) {
+        let y = left(x);
+        (*x).left.set(right(y));
+        if !right(y).is_null() {
+            (*right(y)).parent.set(x);
+        }
+        self.transplant(x, y);
+        (*y).right.set(x);
+        (*x).parent.set(y);
+    }
+
+    unsafe fn insert_fixup(&mut self, mut z: *mut Node) {
+        while is_red(parent(z)) {
+            let mut p = parent(z);
+            let g = parent(p);
+            if p == left(g) {
+                let u = right(g);
+                if is_red(u) {
+                    (*p).red = false;
+                    (*u).red = false;
+                    (*g).red = true;
+                    z = g;
+                } else {
+                    if z == right(p) {
+                        z = p;
+                        self.rotate_left(z);
+                        p = parent(z);
+                    }
+                    (*p).red = false;
+                    (*g).red = true;
+                    self.rotate_right(g);
+                }
+            } else {
+                let u = left(g);
+                if is_red(u) {
+                    (*p).red = false;
+                    (*u).red = false;
+                    (*g).red = true;
+                    z = g;
+                } else {
+                    if z == left(p) {
+                        z = p;
+                        self.rotate_right(z);
+                        p = parent(z);
+                    }
+                    (*p).red = false;
+                    (*g).red = true;
+                    self.rotate_left(g);
+                }
+            }
+        }
+        (*self.root.get()).red = false;
+    }
+
+    unsafe fn remove_fixup(&mut self, mut x: *mut Node, mut x_parent: *mut Node) {
+        while x != self.root.get() && !is_red(x) {
+            if x == left(x_parent) {
+                let mut w = right(x_parent);
+                if is_red(w) {
+                    (*w).red = false;
+                    (*x_parent).red = true;
+                    self.rotate_left(x_parent);
+                    w = right(x_parent);
+                }
+                if !is_red(left(w)) && !is_red(right(w)) {
+                    (*w).red = true;
+                    x = x_parent;
+                    x_parent = parent(x);
+                } else {
+                    if !is_red(right(w)) {
+                        (*left(w)).red = false;
+                        (*w).red = true;
+                        self.rotate_right(w);
+                        w = right(x_parent);
+                    }
+                    (*w).red = (*x_parent).red;
+                    (*x_parent).red = false;
+                    (*right(w)).red = false;
+                    self.rotate_left(x_parent);
+                    x = self.root.get();
+                    break;
+                }
+            } else {
+                let mut w = left(x_parent);
+                if is_red(w) {
+                    (*w).red = false;
+                    (*x_parent).red = true;
+                    self.rotate_right(x_parent);
+                    w = left(x_parent);
+                }
+                if !is_red(left(w)) && !is_red(right(w)) {
+                    (*w).red = true;
+                    x = x_parent;
+                    x_parent = parent(x);
+                } else {
+                    if !is_red(left(w)) {
+                        (*right(w)).red = false;
+                        (*w).red = true;
+                        self.rotate_left(w);
+                        w = left(x_parent);
+                    }
+                    (*w).red = (*x_parent).red;
+                    (*x_parent).red = false;
+                    (*left(w)).red = false;
+                    self.rotate_right(x_parent);
+                    x = self.root.get();
+                    break;
+                }
+            }
+        }
+        if !x.is_null() {
+            (*x).red = false;
+        }
+    }
+
+    /// Number of nodes, or `None` if the red-black or ordering invariants are broken.
+    pub unsafe fn check<F>(&self, less: F) -> Option<usize>
+        where F: Fn(&Node, &Node) -> bool
+    {
+        unsafe fn black_height<F>(node: *mut Node, less: &F, count: &mut usize) -> Option<usize>
+            where F: Fn(&Node, &Node) -> bool
+        {
+            if node.is_null() {
+                return Some(1)
+            }
+            *count += 1;
+            let (l, r) = (left(node), right(node));
+            if is_red(node) && (is_red(l) || is_red(r)) {
+                return None
+            }
+            if !l.is_null() && (parent(l) != node || less(&*node, &*l)) {
+                return None
+            }
+            if !r.is_null() && (parent(r) != node || less(&*r, &*node)) {
+                return None
+            }
+            let lh = black_height(l, less, count)?;
+            let rh = black_height(r, less, count)?;
+            if lh != rh {
+                return None
+            }
+            Some(lh + if is_red(node) { 0 } else { 1 })
+        }
+
+        let root = self.root.get();
+        if is_red(root) || (!root.is_null() && !parent(root).is_null()) {
+            return None
+        }
+        let mut count = 0;
+        black_height(root, &less, &mut count).map(|_| count)
+    }
+}
+
+#[test]
+fn test_rbtree() {
+    use std::mem;
+
+    #[repr(C)]
+    struct Item {
+        node: Node,
+        key: u32,
+    }
+
+    fn key(node: &Node) -> u32 {
+        unsafe { (*(node as *const Node as *const Item)).key }
+    }
+
+    let less = |a: &Node, b: &Node| key(a) < key(b);
+
+    let mut tree: Tree = unsafe { mem::zeroed() };
+    tree.place_new();
+    assert!(tree.is_empty());
+
+    let mut items: Vec<Item> = (0..500u32)
+        .map(|i| Item { node: unsafe { mem::zeroed() }, key: (i * 7919) % 503 })
+        .collect();
+    for item in items.iter_mut() {
+        unsafe { tree.insert(&mut item.node, less) };
+        assert!(unsafe { tree.check(less) }.is_some());
+    }
+    assert_eq!(unsafe { tree.check(less) }, Some(500));
+
+    let mut keys = Vec::new();
+    let mut cur = tree.first();
+    while !cur.is_null() {
+        keys.push(key(unsafe { &*cur }));
+        cur = unsafe { next(cur) };
+    }
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+
+    let found = unsafe { tree.find(|n| key(n).cmp(&42)) };
+    assert_eq!(key(unsafe { &*found }), 42);
+    let lb = unsafe { tree.lower_bound(|n| key(n) < 501) };
+    assert!(key(unsafe { &*lb }) >= 501);
+
+    for (i, item) in items.iter_mut().enumerate() {
+        unsafe { tree.remove(&mut item.node) };
+        assert_eq!(unsafe { tree.check(less) }, Some(500 - i - 1));
+    }
+    assert!(tree.is_empty());
+}
@@ -21,13 +21,25 @@ pub use self::mapped_region::*;
 
 pub struct SegmentManager<A, I> {
     region: mapped_region::MappedRegion,
+    read_only: bool,
     _marker: std::marker::PhantomData<(A, I)>,
 }
 
+mod shared_type;
+pub use self::shared_type::SharedType;
+
 mod segment_manager_impl;
+pub use self::segment_manager_impl::{HeaderError, HeaderInfo, TypeMismatch, type_fingerprint,
+                                     ObjectInfo, ObjectIter, SegmentProblem};
 
 mod managed_shared_memory;
 pub use self::managed_shared_memory::*;
 
 mod managed_mapped_file;
 pub use self::managed_mapped_file::*;
+
+mod managed_heap_memory;
+pub use self::managed_heap_memory::*;
+
+mod managed_external_buffer;
+pub use self::managed_external_buffer::*;
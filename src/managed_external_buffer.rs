@@ -0,0 +1,82 @@
+use sync::NullMutex;
+use mem_algo::{MemAlgo, RbtreeBestFit};
+use indexes::{Index, RbtreeIndex};
+use mapped_region::MappedRegion;
+use SegmentManager;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// A segment manager placed in a buffer provided by the caller, which must
+/// be aligned for the segment header.
+pub struct BasicManagedExternalBuffer<'a, A, I> {
+    segment: SegmentManager<A, I>,
+    _marker: PhantomData<&'a mut [u8]>,
+}
+
+pub type ManagedExternalBuffer<'a> = BasicManagedExternalBuffer<'a, RbtreeBestFit<NullMutex>, RbtreeIndex>;
+
+impl<'a, A: MemAlgo, I: Index> BasicManagedExternalBuffer<'a, A, I> {
+    /// Place an empty segment in `buffer`.
+    pub fn create(buffer: &'a mut [u8]) -> io::Result<Self> {
+        let region = unsafe { MappedRegion::from_raw_parts(buffer.as_mut_ptr() as *mut _, buffer.len()) };
+        Ok(BasicManagedExternalBuffer {
+            segment: SegmentManager::create(region)?,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Attach to the segment already in `buffer`.
+    pub fn open(buffer: &'a mut [u8]) -> io::Result<Self> {
+        let region = unsafe { MappedRegion::from_raw_parts(buffer.as_mut_ptr() as *mut _, buffer.len()) };
+        Ok(BasicManagedExternalBuffer {
+            segment: SegmentManager::open(region)?,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<'a, A, I> Deref for BasicManagedExternalBuffer<'a, A, I> {
+    type Target = SegmentManager<A, I>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.segment
+    }
+}
+
+impl<'a, A, I> DerefMut for BasicManagedExternalBuffer<'a, A, I> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.segment
+    }
+}
+
+#[test]
+fn test_managed_external_buffer() {
+    #[repr(align(16))]
+    struct Buffer([u8; 16 * 1024]);
+
+    let mut first = Buffer([0; 16 * 1024]);
+    let mut second = Buffer([0; 16 * 1024]);
+    {
+        let mut segment = ManagedExternalBuffer::create(&mut first.0).unwrap();
+        segment.construct_from_iter("primes", vec![2u32, 3, 5, 7]).unwrap();
+    }
+
+    // The segment works at whatever address its bytes are copied to.
+    second.0.copy_from_slice(&first.0);
+    let mut segment = ManagedExternalBuffer::open(&mut second.0).unwrap();
+    assert_eq!(segment.find_array::<u32>("primes").unwrap(), Some(&[2, 3, 5, 7][..]));
+    assert!(segment.destroy::<u32>("primes"));
+    drop(segment);
+    assert!(ManagedExternalBuffer::open(&mut first.0).unwrap().find_array::<u32>("primes").unwrap().is_some());
+
+    assert!(ManagedExternalBuffer::create(&mut first.0[1..]).is_err());
+
+    // Every size up to the first that holds a segment is refused.
+    let mut size = 0;
+    while let Err(err) = ManagedExternalBuffer::create(&mut first.0[..size]) {
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        size += 8;
+    }
+    assert!(size > 0);
+}
@@ -0,0 +1,112 @@
+use sync::NullMutex;
+use mem_algo::{MemAlgo, RbtreeBestFit};
+use indexes::{Index, RbtreeIndex};
+use mapped_region::MappedRegion;
+use err::INVALID_ARGUMENT;
+use SegmentManager;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+
+const CHUNK_SIZE: usize = 16;
+
+/// Unit of the heap buffer, so that the segment header is aligned.
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+struct Chunk([u8; CHUNK_SIZE]);
+
+/// A segment manager placed in a buffer on the heap of this process.
+///
+/// Offset pointers keep the segment position independent, so the buffer can
+/// be moved by `grow`, and its bytes can be saved and loaded elsewhere.
+pub struct BasicManagedHeapMemory<A, I> {
+    segment: SegmentManager<A, I>,
+    buffer: Vec<Chunk>,
+}
+
+pub type ManagedHeapMemory = BasicManagedHeapMemory<RbtreeBestFit<NullMutex>, RbtreeIndex>;
+
+impl<A: MemAlgo, I: Index> BasicManagedHeapMemory<A, I> {
+    /// Allocate `size` bytes and place an empty segment in them.
+    pub fn new(size: usize) -> io::Result<Self> {
+        let mut buffer = vec![Chunk([0; CHUNK_SIZE]); size.div_ceil(CHUNK_SIZE)];
+        let region = unsafe { MappedRegion::from_raw_parts(buffer.as_mut_ptr() as *mut _, size) };
+        Ok(BasicManagedHeapMemory {
+            segment: SegmentManager::create(region)?,
+            buffer: buffer,
+        })
+    }
+
+    /// Load a copy of the segment saved from `as_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut buffer = vec![Chunk([0; CHUNK_SIZE]); bytes.len().div_ceil(CHUNK_SIZE)];
+        let region = unsafe {
+            slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, bytes.len()).copy_from_slice(bytes);
+            MappedRegion::from_raw_parts(buffer.as_mut_ptr() as *mut _, bytes.len())
+        };
+        Ok(BasicManagedHeapMemory {
+            segment: SegmentManager::open(region)?,
+            buffer: buffer,
+        })
+    }
+
+    /// Add `extra_bytes` of free memory to the segment. The buffer may move,
+    /// so references into it do not survive this call.
+    pub fn grow(&mut self, extra_bytes: usize) -> io::Result<()> {
+        let size = self.segment.region.size().checked_add(extra_bytes).ok_or(INVALID_ARGUMENT)?;
+        self.buffer.resize(size.div_ceil(CHUNK_SIZE), Chunk([0; CHUNK_SIZE]));
+        let region = unsafe { MappedRegion::from_raw_parts(self.buffer.as_mut_ptr() as *mut _, size) };
+        self.segment.grow(region);
+        Ok(())
+    }
+
+    /// The whole segment, to be stored and loaded again by `from_bytes`.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.buffer.as_ptr() as *const u8, self.segment.region.size()) }
+    }
+}
+
+impl<A, I> Deref for BasicManagedHeapMemory<A, I> {
+    type Target = SegmentManager<A, I>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.segment
+    }
+}
+
+impl<A, I> DerefMut for BasicManagedHeapMemory<A, I> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.segment
+    }
+}
+
+#[test]
+fn test_managed_heap_memory() {
+    let mut heap = ManagedHeapMemory::new(4096).unwrap();
+    heap.construct("first", || 1u64).unwrap();
+    let mut count = 0;
+    while heap.construct(&format!("fill{}", count), || [0u8; 256]).is_some() {
+        count += 1;
+    }
+    assert!(count < 16);
+
+    heap.grow(64 * 1024).unwrap();
+    assert_eq!(heap.find::<u64>("first").unwrap(), Some(&1));
+    for i in count..count + 100 {
+        heap.construct(&format!("fill{}", i), || [i as u8; 256]).unwrap();
+    }
+
+    let copy = ManagedHeapMemory::from_bytes(heap.as_bytes()).unwrap();
+    assert_eq!(copy.as_bytes().len(), 4096 + 64 * 1024);
+    assert_eq!(copy.find::<u64>("first").unwrap(), Some(&1));
+    assert_eq!(copy.find::<[u8; 256]>(&format!("fill{}", count + 99)).unwrap(), Some(&[(count + 99) as u8; 256]));
+    assert!(ManagedHeapMemory::from_bytes(&[0; 4096]).is_err());
+
+    // Every size up to the first that holds a segment is refused.
+    let mut size = 0;
+    while let Err(err) = ManagedHeapMemory::new(size) {
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        size += 8;
+    }
+    assert!(size > 0);
+}
@@ -0,0 +1,169 @@
+use sync::SharedMutex;
+use mem_algo::{MemAlgo, RbtreeBestFit};
+use indexes::{Index, RbtreeIndex};
+use mapped_region::file_mapping;
+use managed_shared_memory::{INIT_TIMEOUT, is_initializing};
+use err::INVALID_ARGUMENT;
+use SegmentManager;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::thread;
+use std::time::Instant;
+
+/// A segment manager placed in a memory mapped file, so its objects
+/// outlive every process and can be reopened later.
+///
+/// Dereferences to the `SegmentManager`, like `BasicManagedSharedMemory`.
+pub struct BasicManagedMappedFile<A, I> {
+    segment: SegmentManager<A, I>,
+}
+
+pub type ManagedMappedFile = BasicManagedMappedFile<RbtreeBestFit<SharedMutex>, RbtreeIndex>;
+
+impl<A: MemAlgo, I: Index> BasicManagedMappedFile<A, I> {
+    /// Create the file `name` of `size` bytes and place an empty segment in
+    /// it. Fails if `name` already exists.
+    pub fn create<T>(name: T, size: usize) -> io::Result<Self>
+        where T: AsRef<str>
+    {
+        let region = file_mapping(name.as_ref()).size(size).create()?;
+        match SegmentManager::create(region) {
+            Ok(segment) => Ok(BasicManagedMappedFile { segment: segment }),
+            Err(err) => {
+                file_mapping(name.as_ref()).remove();
+                Err(err)
+            },
+        }
+    }
+
+    /// Attach to the segment in the existing file `name`.
+    pub fn open<T>(name: T) -> io::Result<Self>
+        where T: AsRef<str>
+    {
+        let region = file_mapping(name).open()?;
+        Ok(BasicManagedMappedFile { segment: SegmentManager::open(region)? })
+    }
+
+    /// Attach to `name` if it exists, otherwise create it with `size` bytes.
+    pub fn open_or_create<T>(name: T, size: usize) -> io::Result<Self>
+        where T: AsRef<str>
+    {
+        let name = name.as_ref();
+        let deadline = Instant::now() + INIT_TIMEOUT;
+        loop {
+            match Self::create(name, size) {
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {},
+                res => return res,
+            }
+            match Self::open(name) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+                Err(ref err) if is_initializing(err) && Instant::now() < deadline => {
+                    thread::yield_now()
+                },
+                res => return res,
+            }
+        }
+    }
+
+    /// Map the segment in `name` without write access. Only lookups work,
+    /// and they do not lock the segment.
+    pub fn open_read_only<T>(name: T) -> io::Result<Self>
+        where T: AsRef<str>
+    {
+        let region = file_mapping(name).read_only().open()?;
+        Ok(BasicManagedMappedFile { segment: SegmentManager::open_read_only(region)? })
+    }
+
+    /// Delete the file `name`.
+    pub fn remove<T>(name: T) -> bool
+        where T: AsRef<str>
+    {
+        file_mapping(name).remove()
+    }
+
+    /// Add `extra_bytes` of free memory to the segment in `name`.
+    /// No process may map it meanwhile.
+    pub fn grow<T>(name: T, extra_bytes: usize) -> io::Result<()>
+        where T: AsRef<str>
+    {
+        let mut segment = Self::open(name.as_ref())?.segment;
+        let size = segment.region.size().checked_add(extra_bytes).ok_or(INVALID_ARGUMENT)?;
+        segment.grow(file_mapping(name).size(size).open()?);
+        Ok(())
+    }
+
+    /// Cut the free memory at the end of the segment in `name` off the
+    /// file, and return its new size. No process may map it meanwhile.
+    pub fn shrink_to_fit<T>(name: T) -> io::Result<usize>
+        where T: AsRef<str>
+    {
+        let size = Self::open(name.as_ref())?.segment.shrink_to_fit();
+        file_mapping(name).truncate(size)?;
+        Ok(size)
+    }
+
+    /// Write the whole segment back to the file.
+    pub fn flush(&self) -> io::Result<()> {
+        self.segment.region.flush()
+    }
+}
+
+impl<A, I> Deref for BasicManagedMappedFile<A, I> {
+    type Target = SegmentManager<A, I>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.segment
+    }
+}
+
+impl<A, I> DerefMut for BasicManagedMappedFile<A, I> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.segment
+    }
+}
+
+#[test]
+fn test_managed_mapped_file() {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let path = env::temp_dir().join(format!("interprocess-test-managed-file-{}", process::id()));
+    let name = path.to_str().unwrap();
+    ManagedMappedFile::remove(name);
+
+    // Sizes too small for a segment are refused, and leave nothing behind.
+    let mut size = 64;
+    while let Err(err) = ManagedMappedFile::create(name, size) {
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+        size += 8;
+    }
+    assert!(ManagedMappedFile::remove(name));
+
+    {
+        let mut file = ManagedMappedFile::create(name, 64 * 1024).unwrap();
+        file.construct("boot_count", || 1u32).unwrap();
+        file.construct_from_iter("names", "abc".bytes()).unwrap();
+        file.flush().unwrap();
+    }
+    assert_eq!(fs::metadata(&path).unwrap().len(), 64 * 1024);
+
+    // Everything is still there once no process maps the file.
+    {
+        let mut file = ManagedMappedFile::open_or_create(name, 1024).unwrap();
+        *file.find_mut::<u32>("boot_count").unwrap().unwrap() += 1;
+        file.flush().unwrap();
+    }
+    ManagedMappedFile::grow(name, 64 * 1024).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 128 * 1024);
+    let size = ManagedMappedFile::shrink_to_fit(name).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), size as u64);
+    assert!(size < 4096);
+
+    let file = ManagedMappedFile::open_read_only(name).unwrap();
+    assert_eq!(file.find::<u32>("boot_count").unwrap(), Some(&2));
+    assert_eq!(file.find_array::<u8>("names").unwrap(), Some(&b"abc"[..]));
+    assert!(ManagedMappedFile::remove(name));
+    assert!(ManagedMappedFile::open(name).is_err());
+}
@@ -0,0 +1,229 @@
+use sync::SharedMutex;
+use mem_algo::{MemAlgo, RbtreeBestFit};
+use indexes::{Index, RbtreeIndex};
+use mapped_region::shared_memory;
+use err::INVALID_ARGUMENT;
+use {SegmentManager, HeaderError};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long `open_or_create` waits for another process to finish
+/// initializing a segment it has just created.
+pub(crate) const INIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether opening failed because the creator has not sized the region or
+/// written the segment header yet.
+pub(crate) fn is_initializing(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::InvalidInput
+        || HeaderError::from_io(err) == Some(HeaderError::Uninitialized)
+}
+
+/// A segment manager placed in a named POSIX shared memory object.
+///
+/// Dereferences to the `SegmentManager`, which provides `construct`, `find`,
+/// `destroy` and the rest of the object placement interface.
+pub struct BasicManagedSharedMemory<A, I> {
+    segment: SegmentManager<A, I>,
+}
+
+pub type ManagedSharedMemory = BasicManagedSharedMemory<RbtreeBestFit<SharedMutex>, RbtreeIndex>;
+
+impl<A: MemAlgo, I: Index> BasicManagedSharedMemory<A, I> {
+    /// Create the shared memory object `name` of `size` bytes and place an
+    /// empty segment in it. Fails if `name` already exists.
+    pub fn create<T>(name: T, size: usize) -> io::Result<Self>
+        where T: AsRef<str>
+    {
+        let region = shared_memory(name.as_ref()).size(size).create()?;
+        match SegmentManager::create(region) {
+            Ok(segment) => Ok(BasicManagedSharedMemory { segment: segment }),
+            Err(err) => {
+                shared_memory(name.as_ref()).remove();
+                Err(err)
+            },
+        }
+    }
+
+    /// Attach to the segment in the existing shared memory object `name`.
+    pub fn open<T>(name: T) -> io::Result<Self>
+        where T: AsRef<str>
+    {
+        let region = shared_memory(name).open()?;
+        Ok(BasicManagedSharedMemory { segment: SegmentManager::open(region)? })
+    }
+
+    /// Attach to `name` if it exists, otherwise create it with `size` bytes.
+    pub fn open_or_create<T>(name: T, size: usize) -> io::Result<Self>
+        where T: AsRef<str>
+    {
+        let name = name.as_ref();
+        let deadline = Instant::now() + INIT_TIMEOUT;
+        loop {
+            match Self::create(name, size) {
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {},
+                res => return res,
+            }
+            match Self::open(name) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+                Err(ref err) if is_initializing(err) && Instant::now() < deadline => {
+                    thread::yield_now()
+                },
+                res => return res,
+            }
+        }
+    }
+
+    /// Map the segment in `name` without write access. Only lookups work,
+    /// and they do not lock the segment.
+    pub fn open_read_only<T>(name: T) -> io::Result<Self>
+        where T: AsRef<str>
+    {
+        let region = shared_memory(name).read_only().open()?;
+        Ok(BasicManagedSharedMemory { segment: SegmentManager::open_read_only(region)? })
+    }
+
+    /// Remove the shared memory object `name`. Processes that still map it
+    /// keep their segment until they drop it.
+    pub fn remove<T>(name: T) -> bool
+        where T: AsRef<str>
+    {
+        shared_memory(name).remove()
+    }
+
+    /// Add `extra_bytes` of free memory to the segment in `name`.
+    /// No process may map it meanwhile.
+    pub fn grow<T>(name: T, extra_bytes: usize) -> io::Result<()>
+        where T: AsRef<str>
+    {
+        let mut segment = Self::open(name.as_ref())?.segment;
+        let size = segment.region.size().checked_add(extra_bytes).ok_or(INVALID_ARGUMENT)?;
+        segment.grow(shared_memory(name).size(size).open()?);
+        Ok(())
+    }
+
+    /// Cut the free memory at the end of the segment in `name` off the
+    /// shared memory object, and return its new size. No process may map it meanwhile.
+    pub fn shrink_to_fit<T>(name: T) -> io::Result<usize>
+        where T: AsRef<str>
+    {
+        let size = Self::open(name.as_ref())?.segment.shrink_to_fit();
+        shared_memory(name).truncate(size)?;
+        Ok(size)
+    }
+}
+
+impl<A, I> Deref for BasicManagedSharedMemory<A, I> {
+    type Target = SegmentManager<A, I>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.segment
+    }
+}
+
+impl<A, I> DerefMut for BasicManagedSharedMemory<A, I> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.segment
+    }
+}
+
+#[test]
+fn test_managed_shared_memory() {
+    use std::process;
+
+    let name = format!("/interprocess-test-managed-shm-{}", process::id());
+    ManagedSharedMemory::remove(&name);
+
+    // Sizes too small for a segment are refused, and leave nothing behind.
+    let mut size = 64;
+    while let Err(err) = ManagedSharedMemory::create(&name, size) {
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!ManagedSharedMemory::remove(&name));
+        size += 8;
+    }
+    assert!(ManagedSharedMemory::remove(&name));
+
+    let mut shm = ManagedSharedMemory::create(&name, 64 * 1024).unwrap();
+    assert_eq!(ManagedSharedMemory::create(&name, 64 * 1024).err().unwrap().kind(),
+               io::ErrorKind::AlreadyExists);
+    shm.construct("counter", || 1u64).unwrap();
+    shm.construct_array("table", 4, |i| i as u32).unwrap();
+    shm.construct_unique(|| 7u64).unwrap();
+    let block = shm.alloc::<u8>(64);
+    let table = shm.find_array::<u32>("table").unwrap().unwrap().as_ptr();
+    let distance = (block as usize).wrapping_sub(table as usize);
+
+    let mut other = ManagedSharedMemory::open_or_create(&name, 64 * 1024).unwrap();
+    *other.find_mut::<u64>("counter").unwrap().unwrap() += 1;
+    assert_eq!(shm.find::<u64>("counter").unwrap(), Some(&2));
+
+    let mut reader = ManagedSharedMemory::open_read_only(&name).unwrap();
+    assert!(reader.is_read_only());
+    assert_eq!(reader.find_array::<u32>("table").unwrap(), Some(&[0, 1, 2, 3][..]));
+    assert_eq!(reader.find_or_construct("missing", || 0u8).unwrap(), None);
+    assert_eq!(reader.find_mut::<u64>("counter").unwrap(), None);
+    assert_eq!(reader.find_array_mut::<u32>("table").unwrap(), None);
+    assert_eq!(reader.find_unique_mut::<u64>().unwrap(), None);
+    assert!(!reader.destroy::<u64>("counter"));
+    assert!(!reader.destroy_unique::<u64>());
+    let free = reader.get_free_memory();
+    let table = reader.find_array::<u32>("table").unwrap().unwrap().as_ptr();
+    reader.dealloc((table as usize).wrapping_add(distance) as *mut u8, 64);
+    assert_eq!(reader.get_free_memory(), free);
+    assert_eq!(reader.find::<u64>("counter").unwrap(), Some(&2));
+    shm.dealloc(block, 64);
+
+    assert!(other.destroy::<u64>("counter"));
+    assert!(shm.find::<u64>("counter").unwrap().is_none());
+    assert!(ManagedSharedMemory::remove(&name));
+    assert!(ManagedSharedMemory::open(&name).is_err());
+
+    // A shared memory object without a segment header is rejected.
+    drop(shared_memory(&name).size(64 * 1024).create().unwrap());
+    let err = ManagedSharedMemory::open(&name).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(HeaderError::from_io(&err), Some(HeaderError::Uninitialized));
+    assert!(ManagedSharedMemory::remove(&name));
+}
+
+#[test]
+fn test_grow_shrink_to_fit() {
+    use std::process;
+
+    let name = format!("/interprocess-test-managed-shm-{}-grow", process::id());
+    ManagedSharedMemory::remove(&name);
+
+    {
+        let mut shm = ManagedSharedMemory::create(&name, 4096).unwrap();
+        let mut i = 0;
+        while shm.construct(&format!("item{}", i), || [i as u8; 200]).is_some() {
+            i += 1;
+        }
+    }
+    ManagedSharedMemory::grow(&name, 128 * 1024).unwrap();
+    {
+        let mut shm = ManagedSharedMemory::open(&name).unwrap();
+        assert_eq!(shm.find::<[u8; 200]>("item0").unwrap(), Some(&[0; 200]));
+        for i in 0..200 {
+            shm.construct(&format!("more{}", i), || [i as u8; 200]).unwrap();
+        }
+        for i in 100..200 {
+            assert!(shm.destroy::<[u8; 200]>(&format!("more{}", i)));
+        }
+    }
+
+    let size = ManagedSharedMemory::shrink_to_fit(&name).unwrap();
+    assert!(size < 4096 + 128 * 1024);
+    {
+        let mut shm = ManagedSharedMemory::open(&name).unwrap();
+        assert_eq!(shm.find::<[u8; 200]>("more99").unwrap(), Some(&[99; 200]));
+        assert!(shm.construct("more100", || [0u8; 200]).is_none());
+    }
+    assert_eq!(ManagedSharedMemory::shrink_to_fit(&name).unwrap(), size);
+    ManagedSharedMemory::grow(&name, 1024).unwrap();
+    assert!(ManagedSharedMemory::open(&name).unwrap().construct("more100", || [0u8; 200]).is_some());
+
+    assert!(ManagedSharedMemory::remove(&name));
+    assert!(ManagedSharedMemory::grow(&name, 1024).is_err());
+}
@@ -5,6 +5,7 @@ use std::io;
 use std::ptr;
 use std::ffi::{CStr, CString};
 use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use libc;
 
 pub struct CopyOnWrite;
@@ -41,16 +42,41 @@ impl Drop for Handle {
 }
 
 /// Unix permission compatible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Perm(u32);
 
+impl Perm {
+    /// Permission bits such as `0o640`.
+    pub fn new(mode: u32) -> Self {
+        Perm(mode & 0o777)
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.0
+    }
+}
+
 pub struct MappedRegion {
     base: *mut libc::c_void,
     size: usize,
     page_offset: usize,
-    is_xsi: bool,
+    /// The XSI segment this region is attached to, if it is not mapped.
+    shmid: Option<i32>,
+    is_borrowed: bool,
 }
 
 impl MappedRegion {
+    /// Wrap memory that the caller owns and keeps alive. It is not unmapped on drop.
+    pub(crate) unsafe fn from_raw_parts(base: *mut libc::c_void, size: usize) -> MappedRegion {
+        MappedRegion {
+            base: base,
+            size: size,
+            page_offset: 0,
+            shmid: None,
+            is_borrowed: true,
+        }
+    }
+
     fn new(mut size: usize, prot: i32, flags: i32, fd: Handle, offset: usize) -> io::Result<Self> {
         let page_offset = adjust_page_offset(offset);
         if size == 0 {
@@ -74,7 +100,8 @@ impl MappedRegion {
                 base: unsafe { base.offset(page_offset as isize) },
                 size: size,
                 page_offset: page_offset,
-                is_xsi: false,
+                shmid: None,
+                is_borrowed: false,
             }),
         }
     }
@@ -86,12 +113,37 @@ impl MappedRegion {
     pub unsafe fn base(&self) -> *mut libc::c_void {
         self.base
     }
+
+    /// Write modified pages back to the mapped file and wait until done.
+    pub fn flush(&self) -> io::Result<()> {
+        match unsafe { libc::msync(self.base.offset(-(self.page_offset as isize)),
+                                   self.size + self.page_offset,
+                                   libc::MS_SYNC) }
+        {
+            -1 => Err(ErrCode::last_error().into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Destroy the XSI segment this region is attached to once every
+    /// process has detached from it. Its key no longer finds it meanwhile.
+    pub fn mark_for_removal(&self) -> io::Result<()> {
+        let shmid = self.shmid.ok_or(INVALID_ARGUMENT)?;
+        match unsafe { libc::shmctl(shmid, libc::IPC_RMID, ptr::null_mut()) } {
+            -1 => Err(ErrCode::last_error().into()),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl Drop for MappedRegion {
     fn drop(&mut self) {
+        if self.is_borrowed {
+            return
+        }
         unsafe {
-            if self.is_xsi {
+            if self.shmid.is_some() {
+                libc::shmdt(self.base);
             } else {
                 libc::munmap(self.base.offset(-(self.page_offset as isize)),
                              self.size + self.page_offset);
@@ -133,7 +185,7 @@ impl<P> FileMapping<P> {
         let fd = self.file_create()?;
         let file_size = self.size + self.offset;
         fd.truncate(file_size)?;
-        MappedRegion::new(self.size, self.mmap_flag, self.mmap_prot, fd, self.offset)
+        MappedRegion::new(self.size, self.mmap_prot, self.mmap_flag, fd, self.offset)
     }
 
     pub fn open(self) -> io::Result<MappedRegion> {
@@ -142,7 +194,7 @@ impl<P> FileMapping<P> {
         if (fd.size()?) < file_size {
             fd.truncate(file_size)?;
         }
-        MappedRegion::new(self.size, self.mmap_flag, self.mmap_prot, fd, self.offset)
+        MappedRegion::new(self.size, self.mmap_prot, self.mmap_flag, fd, self.offset)
     }
 
     pub fn open_or_create(self) -> io::Result<MappedRegion> {
@@ -164,13 +216,20 @@ impl<P> FileMapping<P> {
         if (fd.size()?) < file_size {
             fd.truncate(file_size)?;
         }
-        MappedRegion::new(self.size, self.mmap_flag, self.mmap_prot, fd, self.offset)
+        MappedRegion::new(self.size, self.mmap_prot, self.mmap_flag, fd, self.offset)
     }
 
     pub fn remove(self) -> bool {
         unsafe { libc::unlink(self.name.as_ptr()) == 0 }
     }
 
+    /// Resize the existing file to `size` bytes plus the offset.
+    pub fn truncate(self, size: usize) -> io::Result<()> {
+        let fd = self.file_open()?;
+        fd.truncate(size + self.offset)?;
+        Ok(())
+    }
+
     pub fn offset(self, offset: usize) -> Self {
         FileMapping {
             name: self.name,
@@ -311,7 +370,7 @@ impl<P> SharedMemory<P> {
         let fd = self.shm_create()?;
         let shm_size = self.size + self.offset;
         fd.truncate(shm_size)?;
-        MappedRegion::new(self.size, self.mmap_flag, self.mmap_prot, fd, self.offset)
+        MappedRegion::new(self.size, self.mmap_prot, self.mmap_flag, fd, self.offset)
     }
 
     pub fn open(self) -> io::Result<MappedRegion> {
@@ -320,7 +379,7 @@ impl<P> SharedMemory<P> {
         if (fd.size()?) < shm_size {
             fd.truncate(shm_size)?;
         }
-        MappedRegion::new(self.size, self.mmap_flag, self.mmap_prot, fd, self.offset)
+        MappedRegion::new(self.size, self.mmap_prot, self.mmap_flag, fd, self.offset)
     }
 
     pub fn open_or_create(self) -> io::Result<MappedRegion> {
@@ -342,13 +401,20 @@ impl<P> SharedMemory<P> {
         if (fd.size()?) < shm_size {
             fd.truncate(shm_size)?;
         }
-        MappedRegion::new(self.size, self.mmap_flag, self.mmap_prot, fd, self.offset)
+        MappedRegion::new(self.size, self.mmap_prot, self.mmap_flag, fd, self.offset)
     }
 
     pub fn remove(self) -> bool {
         unsafe { libc::shm_unlink(self.name.as_ptr()) == 0 }
     }
 
+    /// Resize the existing shared memory object to `size` bytes plus the offset.
+    pub fn truncate(self, size: usize) -> io::Result<()> {
+        let fd = self.shm_open()?;
+        fd.truncate(size + self.offset)?;
+        Ok(())
+    }
+
     pub fn offset(self, offset: usize) -> Self {
         SharedMemory {
             name: self.name,
@@ -449,7 +515,7 @@ pub fn shared_memory<T>(name: T) -> SharedMemory<ReadWrite>
         perm: Perm(0o644),
         size: 0,
         offset: 0,
-        shm_flag: 0,
+        shm_flag: libc::O_RDWR,
         mmap_flag: libc::MAP_SHARED,
         mmap_prot: libc::PROT_READ | libc::PROT_WRITE,
         mode: PhantomData,
@@ -464,6 +530,11 @@ impl XsiKey {
         XsiKey(libc::IPC_PRIVATE)
     }
 
+    /// A key as listed by `ipcs`.
+    pub fn from_raw(key: libc::key_t) -> Self {
+        XsiKey(key)
+    }
+
     pub fn new(name: &CStr, id: u8) -> io::Result<Self> {
         if id == 0 {
             return Err(INVALID_ARGUMENT.into());
@@ -476,6 +547,48 @@ impl XsiKey {
     }
 }
 
+/// Status of an XSI segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XsiStat {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub creator_uid: libc::uid_t,
+    pub creator_gid: libc::gid_t,
+    pub perm: Perm,
+    pub size: usize,
+    pub attach_count: usize,
+    pub creator_pid: libc::pid_t,
+    /// Process that attached or detached last.
+    pub last_pid: libc::pid_t,
+    pub attach_time: Option<SystemTime>,
+    pub detach_time: Option<SystemTime>,
+    /// When the segment was created, or its owner or permissions last changed.
+    pub change_time: SystemTime,
+}
+
+impl XsiStat {
+    fn from_shmid_ds(shm: &libc::shmid_ds) -> Self {
+        let time = |secs: libc::time_t| match secs {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs as u64)),
+        };
+        XsiStat {
+            uid: shm.shm_perm.uid,
+            gid: shm.shm_perm.gid,
+            creator_uid: shm.shm_perm.cuid,
+            creator_gid: shm.shm_perm.cgid,
+            perm: Perm::new(shm.shm_perm.mode as u32),
+            size: shm.shm_segsz,
+            attach_count: shm.shm_nattch as usize,
+            creator_pid: shm.shm_cpid,
+            last_pid: shm.shm_lpid,
+            attach_time: time(shm.shm_atime),
+            detach_time: time(shm.shm_dtime),
+            change_time: time(shm.shm_ctime).unwrap_or(UNIX_EPOCH),
+        }
+    }
+}
+
 pub struct XsiSharedMemory<P> {
     key: XsiKey,
     size: usize,
@@ -491,7 +604,8 @@ impl<P> XsiSharedMemory<P> {
             base: self.xsi_at(shmid)?,
             size: self.size,
             page_offset: 0,
-            is_xsi: true,
+            shmid: Some(shmid),
+            is_borrowed: false,
         })
     }
 
@@ -502,7 +616,8 @@ impl<P> XsiSharedMemory<P> {
             base: self.xsi_at(shmid)?,
             size: self.size,
             page_offset: 0,
-            is_xsi: true,
+            shmid: Some(shmid),
+            is_borrowed: false,
         })
     }
 
@@ -526,19 +641,63 @@ impl<P> XsiSharedMemory<P> {
             base: self.xsi_at(shmid)?,
             size: self.size,
             page_offset: 0,
-            is_xsi: true,
+            shmid: Some(shmid),
+            is_borrowed: false,
         })
     }
 
+    /// Destroy the segment once every process has detached from it.
+    /// Processes still attached keep using it, but its key no longer finds it.
     pub fn remove(self) -> bool {
-        if let Ok(shmid) = self.xsi_open() {
-            if let Ok(base) = self.xsi_at(shmid) {
-                unsafe { libc::shmdt(base) == 0 }
-            } else {
-                false
-            }
-        } else {
-            false
+        match self.xsi_open() {
+            Ok(shmid) => unsafe { libc::shmctl(shmid, libc::IPC_RMID, ptr::null_mut()) == 0 },
+            Err(_) => false,
+        }
+    }
+
+    pub fn stat(&self) -> io::Result<XsiStat> {
+        let shm = self.xsi_stat(self.xsi_open()?)?;
+        Ok(XsiStat::from_shmid_ds(&shm))
+    }
+
+    /// Change the permissions of the existing segment. Only its owner,
+    /// its creator or a privileged process may do so.
+    pub fn set_permissions(&self, perm: Perm) -> io::Result<()> {
+        let shmid = self.xsi_open()?;
+        let mut shm = self.xsi_stat(shmid)?;
+        shm.shm_perm.mode = (shm.shm_perm.mode & !0o777) | perm.0 as libc::c_ushort;
+        self.xsi_set(shmid, &mut shm)?;
+        Ok(())
+    }
+
+    /// Hand the existing segment over to another user and group. Only its
+    /// owner, its creator or a privileged process may do so.
+    pub fn set_owner(&self, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+        let shmid = self.xsi_open()?;
+        let mut shm = self.xsi_stat(shmid)?;
+        shm.shm_perm.uid = uid;
+        shm.shm_perm.gid = gid;
+        self.xsi_set(shmid, &mut shm)?;
+        Ok(())
+    }
+
+    pub fn permission(self, perm: Perm) -> Self {
+        XsiSharedMemory {
+            key: self.key,
+            size: self.size,
+            perm: perm,
+            shm_flag: self.shm_flag,
+            mode: self.mode,
+        }
+    }
+
+    pub fn size(self, size: usize) -> Self {
+        XsiSharedMemory {
+            key: self.key,
+            size: size,
+            perm: self.perm,
+            shm_flag: self.shm_flag,
+            mode: self.mode,
         }
     }
 
@@ -563,11 +722,22 @@ impl<P> XsiSharedMemory<P> {
     }
 
     fn xsi_size(&self, shmid: i32) -> Result<usize, ErrCode> {
+        self.xsi_stat(shmid).map(|shm| shm.shm_segsz)
+    }
+
+    fn xsi_stat(&self, shmid: i32) -> Result<libc::shmid_ds, ErrCode> {
         use std::mem;
-        let mut shm: libc::shmid_ds = unsafe { mem::uninitialized() };
+        let mut shm: libc::shmid_ds = unsafe { mem::zeroed() };
         match unsafe { libc::shmctl(shmid, libc::IPC_STAT, &mut shm) } {
             -1 => Err(ErrCode::last_error()),
-            _ => Ok(shm.shm_segsz as usize),
+            _ => Ok(shm),
+        }
+    }
+
+    fn xsi_set(&self, shmid: i32, shm: &mut libc::shmid_ds) -> Result<(), ErrCode> {
+        match unsafe { libc::shmctl(shmid, libc::IPC_SET, shm) } {
+            -1 => Err(ErrCode::last_error()),
+            _ => Ok(()),
         }
     }
 
@@ -625,7 +795,114 @@ pub fn anon_shared_memory(size: usize) -> io::Result<MappedRegion> {
             base: base,
             size: size,
             page_offset: 0,
-            is_xsi: false,
+            shmid: None,
+            is_borrowed: false,
         }),
     }
 }
+
+#[test]
+fn test_xsi_shared_memory() {
+    use std::process;
+
+    /// Only makes system calls, so a forked child may use it.
+    fn shmid_attach_count(shmid: i32) -> Option<usize> {
+        unsafe {
+            let mut shm: libc::shmid_ds = ::std::mem::zeroed();
+            if shmid != -1 && libc::shmctl(shmid, libc::IPC_STAT, &mut shm) == 0 {
+                Some(shm.shm_nattch as usize)
+            } else {
+                None
+            }
+        }
+    }
+
+    fn attach_count(key: &XsiKey) -> Option<usize> {
+        shmid_attach_count(unsafe { libc::shmget(key.0, 0, 0) })
+    }
+
+    let key = XsiKey::from_raw(0x4950_0000 | (process::id() & 0xffff) as libc::key_t);
+    xsi_shared_memory(key.clone()).remove();
+
+    let region = xsi_shared_memory(key.clone()).size(4096).create().unwrap();
+    assert_eq!(region.size(), 4096);
+    assert_eq!(attach_count(&key), Some(1));
+    {
+        let other = xsi_shared_memory(key.clone()).open().unwrap();
+        assert_eq!(attach_count(&key), Some(2));
+        unsafe { *(other.base() as *mut u8) = 7 };
+    }
+    assert_eq!(attach_count(&key), Some(1));
+    assert_eq!(unsafe { *(region.base() as *const u8) }, 7);
+
+    // A child inherits the attachment, attaches once more and detaches both.
+    // It sticks to system calls, because it must not allocate or lock after fork.
+    let shmid = region.shmid.unwrap();
+    match unsafe { libc::fork() } {
+        0 => unsafe {
+            let ok = shmid_attach_count(shmid) == Some(2) && {
+                let addr = libc::shmat(shmid, ptr::null(), 0);
+                addr as isize != -1 && {
+                    *(addr as *mut u8) = 42;
+                    let attached = shmid_attach_count(shmid) == Some(3);
+                    libc::shmdt(addr) == 0 && attached
+                }
+            } && shmid_attach_count(shmid) == Some(2);
+            libc::_exit(if ok { 0 } else { 1 })
+        },
+        pid => {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        },
+    }
+    assert_eq!(attach_count(&key), Some(1));
+    assert_eq!(unsafe { *(region.base() as *const u8) }, 42);
+
+    // Marked for removal, the segment stays usable until the last detach.
+    region.mark_for_removal().unwrap();
+    assert_eq!(attach_count(&key), None);
+    assert!(xsi_shared_memory(key.clone()).open().is_err());
+    unsafe { *(region.base() as *mut u8) = 1 };
+    drop(region);
+    assert!(!xsi_shared_memory(key.clone()).remove());
+
+    xsi_shared_memory(key.clone()).size(4096).create().unwrap();
+    assert!(xsi_shared_memory(key.clone()).remove());
+    assert!(xsi_shared_memory(key.clone()).open().is_err());
+    assert!(anon_shared_memory(4096).unwrap().mark_for_removal().is_err());
+}
+
+#[test]
+fn test_xsi_stat() {
+    use std::process;
+
+    let key = XsiKey::from_raw(0x4950_8000 | (process::id() & 0xffff) as libc::key_t);
+    xsi_shared_memory(key.clone()).remove();
+    assert!(xsi_shared_memory(key.clone()).stat().is_err());
+
+    let before = SystemTime::now() - Duration::from_secs(1);
+    let region = xsi_shared_memory(key.clone()).size(8192).permission(Perm::new(0o640)).create().unwrap();
+    let stat = xsi_shared_memory(key.clone()).stat().unwrap();
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    assert_eq!((stat.uid, stat.gid, stat.creator_uid, stat.creator_gid), (uid, gid, uid, gid));
+    assert_eq!(stat.perm, Perm::new(0o640));
+    assert_eq!(stat.size, 8192);
+    assert_eq!(stat.attach_count, 1);
+    assert_eq!(stat.creator_pid, process::id() as libc::pid_t);
+    assert_eq!(stat.last_pid, process::id() as libc::pid_t);
+    assert!(stat.attach_time.unwrap() >= before);
+    assert_eq!(stat.detach_time, None);
+    assert!(stat.change_time >= before);
+
+    xsi_shared_memory(key.clone()).set_permissions(Perm::new(0o600)).unwrap();
+    assert_eq!(xsi_shared_memory(key.clone()).stat().unwrap().perm.mode(), 0o600);
+    xsi_shared_memory(key.clone()).set_owner(uid, gid).unwrap();
+    drop(region);
+    let stat = xsi_shared_memory(key.clone()).stat().unwrap();
+    assert_eq!(stat.attach_count, 0);
+    assert!(stat.detach_time.is_some());
+
+    assert!(xsi_shared_memory(key.clone()).remove());
+    assert!(xsi_shared_memory(key).set_permissions(Perm::new(0o600)).is_err());
+}
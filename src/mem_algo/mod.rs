@@ -1,10 +1,211 @@
-pub trait MemAlgo {
+use sync;
+use SharedType;
+use std::cmp;
+use std::fmt;
+
+/// A memory algorithm is placed at the start of the memory it manages.
+pub trait MemAlgo: SharedType {
+    /// Mutex family used by the algorithm and by segments built on it.
+    type Mutex: sync::Mutex;
+
+    /// Fewest `segment_bytes` that `place_new` accepts for an algorithm placed at `addr`.
+    fn min_size(addr: usize) -> usize;
+
+    /// Initialize the algorithm over `segment_bytes` bytes counted from `self`.
+    /// Panics if they are fewer than `min_size`.
+    fn place_new(&mut self, segment_bytes: usize);
+
+    /// Take over the memory up to `segment_bytes` counted from `self`, which
+    /// must lie behind the current end and be mapped already.
+    fn grow(&mut self, segment_bytes: usize);
+
+    /// Give up the free memory at the end and return the bytes still in use,
+    /// counted from `self`.
+    fn shrink_to_fit(&mut self) -> usize;
+
     fn alloc<T>(&mut self, size: usize) -> *mut T;
 
     fn dealloc<T>(&mut self, ptr: *mut T, size: usize);
 
     fn realloc<T>(&mut self, ptr: *mut T, size: usize) -> *mut T;
+
+    /// Bytes counted from `self` that the algorithm manages.
+    fn get_size(&self) -> usize;
+
+    /// Bytes in free blocks, including their headers.
+    fn get_free_memory(&self) -> usize;
+
+    /// Call `func` with the address and size of every free block. This does
+    /// not lock, so that it works on read-only mappings.
+    fn free_blocks<F>(&self, func: F) where F: FnMut(*const u8, usize);
+
+    /// Call `func` with the user pointer and usable size of every allocated
+    /// block, in address order. Stops at the first broken block.
+    fn allocated_blocks<F>(&self, func: F) where F: FnMut(*const u8, usize);
+
+    /// Walk every block and return what is wrong with them. Like
+    /// `free_blocks`, this does not lock.
+    fn check_integrity(&self) -> Vec<Problem>;
+
+    fn stats(&self) -> MemStats {
+        let mut stats = MemStats {
+            size: self.get_size(),
+            free_memory: self.get_free_memory(),
+            free_blocks: 0,
+            largest_free_block: 0,
+        };
+        self.free_blocks(|_, size| {
+            stats.free_blocks += 1;
+            stats.largest_free_block = cmp::max(stats.largest_free_block, size);
+        });
+        stats
+    }
+}
+
+/// Something wrong in the memory managed by an algorithm. Offsets are
+/// counted from the algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// A block is smaller than its header, not a multiple of the alignment,
+    /// or runs past the end of the memory.
+    BadBlockSize { offset: usize, size: usize },
+    /// A link in the block at `offset` points outside the memory or at
+    /// something that is not a free block.
+    BadLink { offset: usize },
+    /// The free list is not sorted by address, or its blocks overlap.
+    FreeListOrder { offset: usize },
+    /// The free list or tree does not hold exactly the free blocks.
+    FreeListMismatch { listed: usize, found: usize },
+    /// The boundary tag of a block disagrees with the block in front of it.
+    BadBoundaryTag { offset: usize },
+    /// Two free blocks are next to each other without being merged.
+    UnmergedFreeBlocks { offset: usize },
+    /// The free block tree breaks the red-black tree rules.
+    BadFreeTree,
+    /// The blocks end at `offset` instead of the end of the memory.
+    BadEnd { offset: usize },
+    /// The count of allocated bytes disagrees with the allocated blocks.
+    AllocatedSize { recorded: usize, found: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Problem::BadBlockSize { offset, size } =>
+                write!(f, "block at offset {:#x} has bad size {:#x}", offset, size),
+            Problem::BadLink { offset } =>
+                write!(f, "block at offset {:#x} has a bad link", offset),
+            Problem::FreeListOrder { offset } =>
+                write!(f, "free list is out of order at offset {:#x}", offset),
+            Problem::FreeListMismatch { listed, found } =>
+                write!(f, "{} free blocks are listed, but {} were found", listed, found),
+            Problem::BadBoundaryTag { offset } =>
+                write!(f, "block at offset {:#x} has a bad boundary tag", offset),
+            Problem::UnmergedFreeBlocks { offset } =>
+                write!(f, "free block at offset {:#x} follows another free block", offset),
+            Problem::BadFreeTree =>
+                write!(f, "free block tree is broken"),
+            Problem::BadEnd { offset } =>
+                write!(f, "blocks end at offset {:#x}", offset),
+            Problem::AllocatedSize { recorded, found } =>
+                write!(f, "{} bytes are recorded as allocated, but {} were found", recorded, found),
+        }
+    }
+}
+
+/// Memory usage of a memory algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemStats {
+    pub size: usize,
+    pub free_memory: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+}
+
+impl MemStats {
+    /// Share of the free memory outside the largest free block: 0 when it is
+    /// all in one piece, approaching 1 when it is scattered in small blocks.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free_memory == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free_block as f64 / self.free_memory as f64
+        }
+    }
+}
+
+pub(crate) const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// xorshift, so that the sequences of tests are reproducible.
+#[cfg(test)]
+pub(crate) fn xorshift(mut seed: u64) -> impl FnMut() -> u64 {
+    move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    }
+}
+
+/// Allocate, reallocate and free blocks at random, checking `algo` after
+/// every step, then free the blocks still allocated.
+#[cfg(test)]
+pub(crate) fn alloc_at_random<A: MemAlgo>(algo: &mut A, seed: u64) {
+    use std::ptr;
+    use std::slice;
+
+    #[repr(align(64))]
+    #[allow(dead_code)]
+    struct Align64(u8);
+
+    let mut rand = xorshift(seed);
+    let mut live: Vec<(*mut u8, usize)> = Vec::new();
+    for _ in 0..10000 {
+        let r = rand();
+        if live.is_empty() || !r.is_multiple_of(3) {
+            let size = (rand() % 512) as usize;
+            let ptr = if r.is_multiple_of(5) {
+                let ptr = algo.alloc::<Align64>(size / 64 + 1);
+                assert_eq!(ptr as usize % 64, 0);
+                ptr as *mut u8
+            } else {
+                algo.alloc::<u8>(size)
+            };
+            if !ptr.is_null() {
+                unsafe { ptr::write_bytes(ptr, 0xa5, size) };
+                live.push((ptr, size));
+            }
+        } else if r.is_multiple_of(7) {
+            let i = (rand() as usize) % live.len();
+            let size = (rand() % 1024) as usize;
+            let ptr = algo.realloc(live[i].0, size);
+            if !ptr.is_null() {
+                let keep = cmp::min(live[i].1, size);
+                assert!(unsafe { slice::from_raw_parts(ptr, keep) }.iter().all(|&b| b == 0xa5));
+                unsafe { ptr::write_bytes(ptr, 0xa5, size) };
+                live[i] = (ptr, size);
+            }
+        } else {
+            let i = (rand() as usize) % live.len();
+            let (ptr, size) = live.swap_remove(i);
+            algo.dealloc(ptr, size);
+        }
+        assert_eq!(algo.check_integrity(), []);
+        let mut free = 0;
+        algo.free_blocks(|_, size| free += size);
+        assert_eq!(free, algo.get_free_memory());
+    }
+
+    for (ptr, size) in live.drain(..) {
+        algo.dealloc(ptr, size);
+        assert_eq!(algo.check_integrity(), []);
+    }
 }
 
 mod simple_seq_fit;
 pub use self::simple_seq_fit::*;
+
+mod rbtree_best_fit;
+pub use self::rbtree_best_fit::*;
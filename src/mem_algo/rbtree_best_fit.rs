@@ -0,0 +1,524 @@
+use sync::{Mutex, lock_guard};
+use mem_algo::{MemAlgo, Problem, align_up};
+use SharedType;
+use intrusive::rbtree::{self, Tree, Node};
+use std::cmp;
+use std::ptr;
+use std::mem;
+
+const BLOCK_CTRL_ALIGNMENT: usize = 16;
+
+const ALLOCATED: usize = 1;
+const PREV_ALLOCATED: usize = 2;
+const FLAGS: usize = ALLOCATED | PREV_ALLOCATED;
+
+/// Boundary tag in front of every block. `prev_size` is only meaningful
+/// while the physically previous block is free.
+#[repr(C)]
+struct BlockCtrl {
+    prev_size: usize,
+    size: usize,
+}
+
+const BLOCK_CTRL_SIZE: usize = align_up(mem::size_of::<BlockCtrl>(), BLOCK_CTRL_ALIGNMENT);
+
+/// Free blocks also carry the tree node right behind their `BlockCtrl`.
+const MIN_BLOCK_SIZE: usize = align_up(BLOCK_CTRL_SIZE + mem::size_of::<Node>(), BLOCK_CTRL_ALIGNMENT);
+
+impl BlockCtrl {
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn size(&self) -> usize {
+        self.size & !FLAGS
+    }
+
+    fn is_allocated(&self) -> bool {
+        self.size & ALLOCATED != 0
+    }
+
+    fn is_prev_allocated(&self) -> bool {
+        self.size & PREV_ALLOCATED != 0
+    }
+
+    fn set(&mut self, size: usize, flags: usize) {
+        self.size = size | flags;
+    }
+
+    fn set_prev_allocated(&mut self, allocated: bool) {
+        if allocated {
+            self.size |= PREV_ALLOCATED;
+        } else {
+            self.size &= !PREV_ALLOCATED;
+        }
+    }
+
+    unsafe fn next_block(&self) -> *mut BlockCtrl {
+        (self.addr() + self.size()) as *mut BlockCtrl
+    }
+
+    unsafe fn prev_block(&self) -> *mut BlockCtrl {
+        (self.addr() - self.prev_size) as *mut BlockCtrl
+    }
+
+    fn node(&mut self) -> *mut Node {
+        (self.addr() + BLOCK_CTRL_SIZE) as *mut Node
+    }
+
+    fn from_node(node: *mut Node) -> *mut BlockCtrl {
+        (node as usize - BLOCK_CTRL_SIZE) as *mut BlockCtrl
+    }
+}
+
+/// Free blocks are ordered by size, then by address.
+fn less(a: &Node, b: &Node) -> bool {
+    let a = unsafe { &*BlockCtrl::from_node(a as *const Node as *mut Node) };
+    let b = unsafe { &*BlockCtrl::from_node(b as *const Node as *mut Node) };
+    (a.size(), a.addr()) < (b.size(), b.addr())
+}
+
+/// Best-fit allocator keeping free blocks in a red-black tree sorted by size.
+/// Neighbours are merged in constant time through boundary tags.
+#[repr(C)]
+pub struct RbtreeBestFit<M> {
+    size: usize,
+    allocate_size: usize,
+    free_blocks: Tree,
+    mutex: M,
+}
+
+impl<M> RbtreeBestFit<M>
+    where M: Mutex
+{
+    fn first_block(&self) -> usize {
+        align_up(self as *const Self as usize + mem::size_of::<Self>(), BLOCK_CTRL_ALIGNMENT)
+    }
+
+    fn end_block(&self, segment_bytes: usize) -> usize {
+        ((self as *const Self as usize + segment_bytes) & !(BLOCK_CTRL_ALIGNMENT - 1)) - BLOCK_CTRL_SIZE
+    }
+
+    fn block_size(size: usize) -> Option<usize> {
+        size.checked_add(BLOCK_CTRL_SIZE + BLOCK_CTRL_ALIGNMENT - 1)
+            .map(|size| cmp::max(size & !(BLOCK_CTRL_ALIGNMENT - 1), MIN_BLOCK_SIZE))
+    }
+
+    /// Start of a block inside `block` whose user pointer is aligned to `align`,
+    /// leaving either no gap or one large enough to stay a free block.
+    fn aligned_start(block: &BlockCtrl, align: usize) -> usize {
+        let addr = block.addr();
+        let mut start = align_up(addr + BLOCK_CTRL_SIZE, align) - BLOCK_CTRL_SIZE;
+        while start != addr && start - addr < MIN_BLOCK_SIZE {
+            start += align;
+        }
+        start
+    }
+
+    unsafe fn alloc_block(&mut self, need: usize, align: usize) -> *mut BlockCtrl {
+        let mut node = self.free_blocks.lower_bound(|n| (*BlockCtrl::from_node(n as *const Node as *mut Node)).size() < need);
+        while !node.is_null() {
+            let block = &*BlockCtrl::from_node(node);
+            if Self::aligned_start(block, align) + need <= block.addr() + block.size() {
+                break
+            }
+            node = rbtree::next(node);
+        }
+        if node.is_null() {
+            return ptr::null_mut()
+        }
+        self.free_blocks.remove(node);
+
+        let free = &mut *BlockCtrl::from_node(node);
+        let addr = free.addr();
+        let end = addr + free.size();
+        let start = Self::aligned_start(free, align);
+        let mut flags = free.size & PREV_ALLOCATED;
+
+        // The alignment gap in front stays free.
+        if start > addr {
+            free.set(start - addr, flags);
+            self.free_blocks.insert(free.node(), less);
+            flags = 0;
+        }
+
+        let mut need = need;
+        if end - (start + need) < MIN_BLOCK_SIZE {
+            need = end - start;
+        }
+
+        let block = &mut *(start as *mut BlockCtrl);
+        if flags == 0 {
+            block.prev_size = start - addr;
+        }
+        block.set(need, flags | ALLOCATED);
+
+        let next = &mut *block.next_block();
+        if start + need < end {
+            next.set(end - (start + need), PREV_ALLOCATED);
+            (*next.next_block()).prev_size = next.size();
+            self.free_blocks.insert(next.node(), less);
+        } else {
+            next.set_prev_allocated(true);
+        }
+
+        self.allocate_size += need;
+        block
+    }
+
+    unsafe fn free_block(&mut self, block: *mut BlockCtrl) {
+        let mut block = &mut *block;
+        let mut size = block.size();
+
+        let next = &mut *block.next_block();
+        if !next.is_allocated() {
+            self.free_blocks.remove(next.node());
+            size += next.size();
+        }
+
+        if !block.is_prev_allocated() {
+            let prev = &mut *block.prev_block();
+            self.free_blocks.remove(prev.node());
+            size += prev.size();
+            block = prev;
+        }
+
+        let flags = block.size & PREV_ALLOCATED;
+        block.set(size, flags);
+        let next = &mut *block.next_block();
+        next.prev_size = size;
+        next.set_prev_allocated(false);
+        self.free_blocks.insert(block.node(), less);
+    }
+
+    unsafe fn shrink_block(&mut self, block: *mut BlockCtrl, need: usize) {
+        let block = &mut *block;
+        let rest = block.size() - need;
+        if rest >= MIN_BLOCK_SIZE {
+            let flags = block.size & FLAGS;
+            block.set(need, flags);
+            let tail = &mut *block.next_block();
+            tail.set(rest, ALLOCATED | PREV_ALLOCATED);
+            self.allocate_size -= rest;
+            self.free_block(tail);
+        }
+    }
+
+    unsafe fn expand_block(&mut self, block: *mut BlockCtrl, need: usize) -> bool {
+        let block = &mut *block;
+        let next = &mut *block.next_block();
+        if next.is_allocated() || block.size() + next.size() < need {
+            return false
+        }
+
+        self.free_blocks.remove(next.node());
+        self.allocate_size += next.size();
+        let flags = block.size & FLAGS;
+        let total = block.size() + next.size();
+        block.set(total, flags);
+        (*block.next_block()).set_prev_allocated(true);
+        self.shrink_block(block, need);
+        true
+    }
+}
+
+unsafe impl<M> SharedType for RbtreeBestFit<M> {
+    const NAME: &'static str = "RbtreeBestFit";
+}
+
+impl<M> MemAlgo for RbtreeBestFit<M>
+    where M: Mutex
+{
+    type Mutex = M;
+
+    fn min_size(addr: usize) -> usize {
+        align_up(addr + mem::size_of::<Self>(), BLOCK_CTRL_ALIGNMENT) + MIN_BLOCK_SIZE + BLOCK_CTRL_SIZE - addr
+    }
+
+    fn place_new(&mut self, segment_bytes: usize) {
+        self.mutex.place_new();
+
+        let first = self.first_block();
+        let end = self.end_block(segment_bytes);
+        assert!(first + MIN_BLOCK_SIZE <= end, "segment too small");
+
+        self.size = segment_bytes;
+        self.allocate_size = 0;
+        self.free_blocks.place_new();
+
+        unsafe {
+            let block = &mut *(first as *mut BlockCtrl);
+            block.set(end - first, PREV_ALLOCATED);
+
+            // A zero sized allocated block at the end stops coalescing.
+            let sentinel = &mut *(end as *mut BlockCtrl);
+            sentinel.prev_size = end - first;
+            sentinel.set(0, ALLOCATED);
+
+            self.free_blocks.insert(block.node(), less);
+        }
+    }
+
+    fn grow(&mut self, segment_bytes: usize) {
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let old_end = self.end_block(self.size);
+        let end = self.end_block(segment_bytes);
+        if end <= old_end {
+            return
+        }
+
+        unsafe {
+            // The old sentinel becomes an allocated block in front of the new
+            // one and is freed, merging with a free block in front of it.
+            let block = &mut *(old_end as *mut BlockCtrl);
+            if block.is_prev_allocated() && end - old_end < MIN_BLOCK_SIZE {
+                // Too small to be a free block on its own, so leave the tail
+                // unused until the segment grows further.
+                return
+            }
+            let flags = block.size & PREV_ALLOCATED;
+            block.set(end - old_end, flags | ALLOCATED);
+
+            let sentinel = &mut *(end as *mut BlockCtrl);
+            sentinel.set(0, ALLOCATED | PREV_ALLOCATED);
+
+            self.size = segment_bytes;
+            self.free_block(block);
+        }
+    }
+
+    fn shrink_to_fit(&mut self) -> usize {
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        unsafe {
+            let sentinel = &mut *(self.end_block(self.size) as *mut BlockCtrl);
+            if !sentinel.is_prev_allocated() {
+                // The free block in front of the sentinel becomes the new one.
+                let block = &mut *sentinel.prev_block();
+                self.free_blocks.remove(block.node());
+                let flags = block.size & PREV_ALLOCATED;
+                block.set(0, flags | ALLOCATED);
+                self.size = block.addr() + BLOCK_CTRL_SIZE - self as *const Self as usize;
+            }
+        }
+        self.size
+    }
+
+    fn get_size(&self) -> usize {
+        self.size
+    }
+
+    fn get_free_memory(&self) -> usize {
+        self.end_block(self.size) - self.first_block() - self.allocate_size
+    }
+
+    fn free_blocks<F>(&self, mut func: F)
+        where F: FnMut(*const u8, usize)
+    {
+        let mut node = self.free_blocks.first();
+        while !node.is_null() {
+            let block = unsafe { &*BlockCtrl::from_node(node) };
+            func(block.addr() as *const u8, block.size());
+            node = unsafe { rbtree::next(node) };
+        }
+    }
+
+    fn allocated_blocks<F>(&self, mut func: F)
+        where F: FnMut(*const u8, usize)
+    {
+        let end = self.end_block(self.size);
+        let mut addr = self.first_block();
+        while addr < end {
+            let block = unsafe { &*(addr as *const BlockCtrl) };
+            if block.size() < BLOCK_CTRL_SIZE || block.size() > end - addr {
+                break
+            }
+            if block.is_allocated() {
+                func((addr + BLOCK_CTRL_SIZE) as *const u8, block.size() - BLOCK_CTRL_SIZE);
+            }
+            addr += block.size();
+        }
+    }
+
+    fn check_integrity(&self) -> Vec<Problem> {
+        let this = self as *const Self as usize;
+        let end = self.end_block(self.size);
+        let mut problems = Vec::new();
+
+        // The blocks tile the memory, and their boundary tags match.
+        let mut addr = self.first_block();
+        let mut prev_allocated = true;
+        let mut prev_size = 0;
+        let mut allocated = 0;
+        let mut free_nodes = Vec::new();
+        while addr < end {
+            let block = unsafe { &*(addr as *const BlockCtrl) };
+            let size = block.size();
+            if size < BLOCK_CTRL_SIZE || size % BLOCK_CTRL_ALIGNMENT != 0 || size > end - addr
+                || (!block.is_allocated() && size < MIN_BLOCK_SIZE)
+            {
+                problems.push(Problem::BadBlockSize { offset: addr - this, size: size });
+                return problems
+            }
+            if block.is_prev_allocated() != prev_allocated || (!prev_allocated && block.prev_size != prev_size) {
+                problems.push(Problem::BadBoundaryTag { offset: addr - this });
+            }
+            if block.is_allocated() {
+                allocated += size;
+            } else {
+                if !prev_allocated {
+                    problems.push(Problem::UnmergedFreeBlocks { offset: addr - this });
+                }
+                free_nodes.push(addr + BLOCK_CTRL_SIZE);
+            }
+            prev_allocated = block.is_allocated();
+            prev_size = size;
+            addr += size;
+        }
+
+        let sentinel = unsafe { &*(end as *const BlockCtrl) };
+        if !sentinel.is_allocated() || sentinel.size() != 0 {
+            problems.push(Problem::BadEnd { offset: addr - this });
+        } else if sentinel.is_prev_allocated() != prev_allocated || (!prev_allocated && sentinel.prev_size != prev_size) {
+            problems.push(Problem::BadBoundaryTag { offset: end - this });
+        }
+        if allocated != self.allocate_size {
+            problems.push(Problem::AllocatedSize { recorded: self.allocate_size, found: allocated });
+        }
+
+        // Only follow the tree once every link is known to hit a free block.
+        let is_node = |node: *mut Node| node.is_null() || free_nodes.binary_search(&(node as usize)).is_ok();
+        if !is_node(self.free_blocks.root()) {
+            problems.push(Problem::BadFreeTree);
+            return problems
+        }
+        for &node in free_nodes.iter() {
+            let node = node as *mut Node;
+            let links = unsafe { [rbtree::parent(node), rbtree::left(node), rbtree::right(node)] };
+            if !links.iter().all(|&link| is_node(link)) {
+                problems.push(Problem::BadLink { offset: node as usize - BLOCK_CTRL_SIZE - this });
+                return problems
+            }
+        }
+        match unsafe { self.free_blocks.check(less) } {
+            Some(listed) if listed != free_nodes.len() => {
+                problems.push(Problem::FreeListMismatch { listed: listed, found: free_nodes.len() });
+            },
+            Some(_) => {},
+            None => problems.push(Problem::BadFreeTree),
+        }
+        problems
+    }
+
+    fn alloc<T>(&mut self, size: usize) -> *mut T {
+        let need = match size.checked_mul(mem::size_of::<T>()).and_then(Self::block_size) {
+            Some(need) => need,
+            None => return ptr::null_mut(),
+        };
+        let align = cmp::max(mem::align_of::<T>(), BLOCK_CTRL_ALIGNMENT);
+
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let block = unsafe { self.alloc_block(need, align) };
+        if block.is_null() {
+            ptr::null_mut()
+        } else {
+            (block as usize + BLOCK_CTRL_SIZE) as *mut T
+        }
+    }
+
+    fn dealloc<T>(&mut self, ptr: *mut T, _size: usize) {
+        if ptr.is_null() {
+            return
+        }
+
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let block = (ptr as usize - BLOCK_CTRL_SIZE) as *mut BlockCtrl;
+        unsafe {
+            debug_assert!((*block).is_allocated(), "double free");
+            self.allocate_size -= (*block).size();
+            self.free_block(block);
+        }
+    }
+
+    fn realloc<T>(&mut self, ptr: *mut T, size: usize) -> *mut T {
+        if ptr.is_null() {
+            return self.alloc(size)
+        }
+        let need = match size.checked_mul(mem::size_of::<T>()).and_then(Self::block_size) {
+            Some(need) => need,
+            None => return ptr::null_mut(),
+        };
+        let align = cmp::max(mem::align_of::<T>(), BLOCK_CTRL_ALIGNMENT);
+
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let block = (ptr as usize - BLOCK_CTRL_SIZE) as *mut BlockCtrl;
+        unsafe {
+            let old = (*block).size();
+            if need <= old {
+                self.shrink_block(block, need);
+                return ptr
+            }
+            if self.expand_block(block, need) {
+                return ptr
+            }
+
+            let new = self.alloc_block(need, align);
+            if new.is_null() {
+                return ptr::null_mut()
+            }
+            let new_ptr = ((new as usize) + BLOCK_CTRL_SIZE) as *mut T;
+            ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, old - BLOCK_CTRL_SIZE);
+            self.allocate_size -= old;
+            self.free_block(block);
+            new_ptr
+        }
+    }
+}
+
+#[test]
+fn test_alloc() {
+    use mem_algo::alloc_at_random;
+    use sync::NullMutex;
+
+    const SEGMENT_SIZE: usize = 64 * 1024;
+    let mut buf = vec![0u64; SEGMENT_SIZE / 8];
+    let base: &mut RbtreeBestFit<NullMutex> = unsafe { &mut *(buf.as_mut_ptr() as *mut _) };
+    base.place_new(SEGMENT_SIZE);
+    assert_eq!(base.check_integrity(), []);
+
+    alloc_at_random(base, 0x9e3779b97f4a7c15);
+    assert_eq!(base.allocate_size, 0);
+    assert_eq!(unsafe { base.free_blocks.check(less) }, Some(1));
+    assert_eq!(base.stats().free_blocks, 1);
+    assert_eq!(base.stats().largest_free_block, base.get_free_memory());
+
+    // The new tail merges with the free block in front of it.
+    let ptr = base.alloc::<u8>(SEGMENT_SIZE / 2);
+    assert!(!ptr.is_null());
+    assert!(base.alloc::<u8>(SEGMENT_SIZE / 2).is_null());
+    buf.resize(SEGMENT_SIZE / 4, 0);
+    let base: &mut RbtreeBestFit<NullMutex> = unsafe { &mut *(buf.as_mut_ptr() as *mut _) };
+    base.grow(SEGMENT_SIZE + 16);
+    assert_eq!(base.check_integrity(), []);
+    base.grow(SEGMENT_SIZE * 2);
+    assert_eq!(base.check_integrity(), []);
+    let ptr = base.alloc::<u8>(SEGMENT_SIZE);
+    assert!(!ptr.is_null());
+    assert_eq!(base.check_integrity(), []);
+
+    let size = base.shrink_to_fit();
+    assert_eq!(base.check_integrity(), []);
+    assert_eq!(size, ptr as usize + SEGMENT_SIZE + BLOCK_CTRL_SIZE - base as *const _ as usize);
+    assert_eq!(base.shrink_to_fit(), size);
+    base.dealloc(ptr, SEGMENT_SIZE);
+    assert_eq!(base.check_integrity(), []);
+
+    // Corruption is reported instead of followed.
+    let ptr = base.alloc::<u8>(100);
+    let block = (ptr as usize - BLOCK_CTRL_SIZE) as *mut BlockCtrl;
+    let offset = block as usize - base as *const _ as usize;
+    base.allocate_size += 16;
+    assert_eq!(base.check_integrity(), [Problem::AllocatedSize { recorded: base.allocate_size, found: base.allocate_size - 16 }]);
+    base.allocate_size -= 16;
+    unsafe { (*block).size += 8 };
+    assert_eq!(base.check_integrity(), [Problem::BadBlockSize { offset: offset, size: unsafe { (*block).size() } }]);
+}
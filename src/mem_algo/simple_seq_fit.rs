@@ -1,5 +1,7 @@
-use sync::Mutex;
-use mem_algo::MemAlgo;
+use sync::{Mutex, lock_guard};
+use mem_algo::{MemAlgo, Problem, align_up};
+use SharedType;
+use std::cmp;
 use std::ptr;
 use std::mem;
 use std::ops::{Deref, DerefMut};
@@ -18,7 +20,12 @@ struct OffsetCell<T> {
 impl<T> OffsetCell<T> {
     pub fn set(&mut self, ptr: *const T) {
         let this = self as *const Self as usize;
-        self.offset = ptr as usize - this;
+        self.offset = (ptr as usize).wrapping_sub(this);
+    }
+
+    pub fn get(&self) -> *mut T {
+        let this = self as *const Self as usize;
+        self.offset.wrapping_add(this) as *mut T
     }
 }
 
@@ -26,25 +33,46 @@ impl<T> Deref for OffsetCell<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        let this = self as *const Self as usize;
-        unsafe { &*((self.offset + this) as *const T) }
+        unsafe { &*self.get() }
     }
 }
 
 impl<T> DerefMut for OffsetCell<T> {
     fn deref_mut(&mut self) -> &mut T {
-        let this = self as *const Self as usize;
-        unsafe { &mut *((self.offset + this) as *mut T) }
+        unsafe { &mut *self.get() }
     }
 }
 
 const BLOCK_CTRL_ALIGNMENT: usize = 16;
 
+/// Size of the header in front of every block, rounded up so that the
+/// user pointer right behind it keeps `BLOCK_CTRL_ALIGNMENT`.
+const BLOCK_CTRL_SIZE: usize = align_up(mem::size_of::<BlockCtrl>(), BLOCK_CTRL_ALIGNMENT);
+
+/// Free blocks are kept in a circular list sorted by address, starting and
+/// ending at `SimpleSeqFit::root`. Allocated blocks point `next` at themselves,
+/// which is an offset of 0 only because `next` is the first field.
+#[repr(C)]
 struct BlockCtrl {
     next: OffsetCell<BlockCtrl>,
     size: usize,
 }
 
+impl BlockCtrl {
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end(&self) -> usize {
+        self.addr() + self.size
+    }
+
+    fn is_allocated(&self) -> bool {
+        self.next.offset == 0
+    }
+}
+
+#[repr(C)]
 pub struct SimpleSeqFit<M> {
     root: BlockCtrl,
     allocate_size: usize,
@@ -54,211 +82,401 @@ pub struct SimpleSeqFit<M> {
 impl<M> SimpleSeqFit<M>
     where M: Mutex
 {
+    fn first_block(&self) -> usize {
+        align_up(self as *const Self as usize + mem::size_of::<Self>(), BLOCK_CTRL_ALIGNMENT)
+    }
+
+    fn last_block(&self) -> usize {
+        (self as *const Self as usize + self.root.size) & !(BLOCK_CTRL_ALIGNMENT - 1)
+    }
+
+    /// Bytes of a block holding `size` bytes of user data.
+    fn block_size(size: usize) -> Option<usize> {
+        size.checked_add(BLOCK_CTRL_SIZE + BLOCK_CTRL_ALIGNMENT - 1)
+            .map(|size| size & !(BLOCK_CTRL_ALIGNMENT - 1))
+    }
+
+    /// First-fit search for a block of `need` bytes whose user pointer is aligned to `align`.
+    unsafe fn alloc_block(&mut self, need: usize, align: usize) -> *mut BlockCtrl {
+        let root = &mut self.root as *mut BlockCtrl;
+        let mut prev = root;
+        let mut cur = self.root.next.get();
+
+        while cur != root {
+            let addr = (*cur).addr();
+            let end = (*cur).end();
+            let start = align_up(addr + BLOCK_CTRL_SIZE, align) - BLOCK_CTRL_SIZE;
+
+            if start + need <= end {
+                let next = (*cur).next.get();
+                let mut need = need;
+                if end - (start + need) < BLOCK_CTRL_SIZE {
+                    need = end - start;
+                }
+
+                // Any padding in front of an over-aligned block stays free.
+                let mut link = prev;
+                if start > addr {
+                    (*cur).size = start - addr;
+                    link = cur;
+                }
+
+                if start + need < end {
+                    let rest = (start + need) as *mut BlockCtrl;
+                    (*rest).size = end - (start + need);
+                    (*rest).next.set(next);
+                    (*link).next.set(rest);
+                } else {
+                    (*link).next.set(next);
+                }
+
+                let block = start as *mut BlockCtrl;
+                (*block).size = need;
+                (*block).next.offset = 0;
+                self.allocate_size += need;
+                return block
+            }
+
+            prev = cur;
+            cur = (*cur).next.get();
+        }
+
+        ptr::null_mut()
+    }
+
+    /// Return a block to the free list, merging it with adjacent free blocks.
+    unsafe fn free_block(&mut self, block: *mut BlockCtrl) {
+        let root = &mut self.root as *mut BlockCtrl;
+        let addr = (*block).addr();
+        let mut size = (*block).size;
+
+        let mut prev = root;
+        let mut cur = self.root.next.get();
+        while cur != root && (*cur).addr() < addr {
+            prev = cur;
+            cur = (*cur).next.get();
+        }
+
+        let mut next = cur;
+        if cur != root && addr + size == (*cur).addr() {
+            size += (*cur).size;
+            next = (*cur).next.get();
+        }
+
+        if prev != root && (*prev).end() == addr {
+            (*prev).size += size;
+            (*prev).next.set(next);
+        } else {
+            (*block).size = size;
+            (*block).next.set(next);
+            (*prev).next.set(block);
+        }
+    }
+
+    /// Give the tail of an allocated block beyond `need` bytes back to the free list.
+    unsafe fn shrink_block(&mut self, block: *mut BlockCtrl, need: usize) {
+        let rest = (*block).size - need;
+        if rest >= BLOCK_CTRL_SIZE {
+            (*block).size = need;
+            self.allocate_size -= rest;
+
+            let tail = ((*block).addr() + need) as *mut BlockCtrl;
+            (*tail).size = rest;
+            self.free_block(tail);
+        }
+    }
+
+    /// Grow an allocated block in place by taking from the free block right behind it.
+    unsafe fn expand_block(&mut self, block: *mut BlockCtrl, need: usize) -> bool {
+        let root = &mut self.root as *mut BlockCtrl;
+        let end = (*block).end();
+
+        let mut prev = root;
+        let mut cur = self.root.next.get();
+        while cur != root && (*cur).addr() < end {
+            prev = cur;
+            cur = (*cur).next.get();
+        }
+
+        if cur == root || (*cur).addr() != end || (*block).size + (*cur).size < need {
+            return false
+        }
+
+        let next = (*cur).next.get();
+        let total = (*block).size + (*cur).size;
+        (*prev).next.set(next);
+        self.allocate_size += (*cur).size;
+        (*block).size = total;
+        self.shrink_block(block, need);
+        true
+    }
+}
+
+unsafe impl<M> SharedType for SimpleSeqFit<M> {
+    const NAME: &'static str = "SimpleSeqFit";
+}
+
+impl<M> MemAlgo for SimpleSeqFit<M>
+    where M: Mutex
+{
+    type Mutex = M;
+
+    fn min_size(addr: usize) -> usize {
+        align_up(addr + mem::size_of::<Self>(), BLOCK_CTRL_ALIGNMENT) + BLOCK_CTRL_SIZE - addr
+    }
+
     fn place_new(&mut self, segment_bytes: usize) {
         self.mutex.place_new();
 
+        let block1 = self.first_block();
+        let end = (self as *const Self as usize + segment_bytes) & !(BLOCK_CTRL_ALIGNMENT - 1);
+        assert!(block1 + BLOCK_CTRL_SIZE <= end, "segment too small");
+
         let root = &self.root as *const BlockCtrl;
-        let block1 = align(root, mem::align_of::<Self>());
         self.allocate_size = 0;
         self.root.size = segment_bytes;
-        self.root.next.set(block1);
-        self.root.next.size = 0;
+        self.root.next.set(block1 as *const BlockCtrl);
+        self.root.next.size = end - block1;
         self.root.next.next.set(root);
     }
 
-    fn sanity_check(&self) -> bool {
+    fn grow(&mut self, segment_bytes: usize) {
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let old_last = self.last_block();
+        self.root.size = cmp::max(self.root.size, segment_bytes);
+        let last = self.last_block();
+        if last > old_last {
+            unsafe {
+                let block = old_last as *mut BlockCtrl;
+                (*block).size = last - old_last;
+                self.free_block(block);
+            }
+        }
+    }
+
+    fn shrink_to_fit(&mut self) -> usize {
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let root = &mut self.root as *mut BlockCtrl;
+        let this = self as *const Self as usize;
+        unsafe {
+            let mut prev = root;
+            let mut cur = self.root.next.get();
+            while cur != root && (*cur).next.get() != root {
+                prev = cur;
+                cur = (*cur).next.get();
+            }
+            if cur != root && (*cur).end() == self.last_block() {
+                (*prev).next.set(root);
+                self.root.size = (*cur).addr() - this;
+            }
+        }
+        self.root.size
+    }
+
+    fn get_size(&self) -> usize {
+        self.root.size
+    }
+
+    fn get_free_memory(&self) -> usize {
+        self.last_block() - self.first_block() - self.allocate_size
+    }
+
+    fn free_blocks<F>(&self, mut func: F)
+        where F: FnMut(*const u8, usize)
+    {
         let root = &self.root as *const BlockCtrl;
-        let mut cur = &*self.root.next as *const BlockCtrl;
+        let mut cur = self.root.next.get() as *const BlockCtrl;
+        while cur != root {
+            let block = unsafe { &*cur };
+            func(cur as *const u8, block.size);
+            cur = block.next.get();
+        }
+    }
 
-        if cur < root {
-            return false
+    fn allocated_blocks<F>(&self, mut func: F)
+        where F: FnMut(*const u8, usize)
+    {
+        let last = self.last_block();
+        let mut addr = self.first_block();
+        while addr < last {
+            let block = unsafe { &*(addr as *const BlockCtrl) };
+            if block.size < BLOCK_CTRL_SIZE || block.size > last - addr {
+                break
+            }
+            if block.is_allocated() {
+                func((addr + BLOCK_CTRL_SIZE) as *const u8, block.size - BLOCK_CTRL_SIZE);
+            }
+            addr += block.size;
         }
+    }
 
-        let last = (root as usize + self.root.size) as *const BlockCtrl;
-        if cur > last {
-            return false
+    fn check_integrity(&self) -> Vec<Problem> {
+        let this = self as *const Self as usize;
+        let root = &self.root as *const BlockCtrl as usize;
+        let first = self.first_block();
+        let last = self.last_block();
+        let bad_size = |addr: usize, size: usize| {
+            size < BLOCK_CTRL_SIZE || size & (BLOCK_CTRL_ALIGNMENT - 1) != 0 || size > last - addr
+        };
+        let mut problems = Vec::new();
+
+        // The blocks tile the memory and the allocated ones add up.
+        let mut allocated = 0;
+        let mut free = 0;
+        let mut addr = first;
+        while addr < last {
+            let block = unsafe { &*(addr as *const BlockCtrl) };
+            if bad_size(addr, block.size) {
+                problems.push(Problem::BadBlockSize { offset: addr - this, size: block.size });
+                return problems
+            }
+            if block.is_allocated() {
+                allocated += block.size;
+            } else {
+                free += 1;
+            }
+            addr += block.size;
+        }
+        if allocated != self.allocate_size {
+            problems.push(Problem::AllocatedSize { recorded: self.allocate_size, found: allocated });
         }
 
+        // The free list is sorted, so it cannot run in circles.
+        let mut listed = 0;
+        let mut prev_end = first;
+        let mut cur = self.root.next.get() as usize;
         while cur != root {
-            let next = unsafe { &*(*cur).next } as *const BlockCtrl;
-            if next > last {
-                return false
+            if cur < first || cur >= last || cur & (BLOCK_CTRL_ALIGNMENT - 1) != 0 {
+                problems.push(Problem::BadLink { offset: cur.wrapping_sub(this) });
+                return problems
             }
-            if next < cur {
-                return false
+            let block = unsafe { &*(cur as *const BlockCtrl) };
+            if cur < prev_end || block.is_allocated() {
+                problems.push(Problem::FreeListOrder { offset: cur - this });
+                return problems
             }
-            cur = next;
+            if bad_size(cur, block.size) {
+                problems.push(Problem::BadBlockSize { offset: cur - this, size: block.size });
+                return problems
+            }
+            listed += 1;
+            prev_end = block.end();
+            cur = block.next.get() as usize;
         }
-
-        return true
+        if listed != free {
+            problems.push(Problem::FreeListMismatch { listed: listed, found: free });
+        }
+        problems
     }
-}
 
-impl<M> MemAlgo for SimpleSeqFit<M>
-    where M: Mutex
-{
     fn alloc<T>(&mut self, size: usize) -> *mut T {
-        let mut cur = &mut *self.root.next as *mut BlockCtrl;
-        let mut prev = &mut self.root as *mut BlockCtrl;
-        let root = prev;
-        ptr::null_mut()
+        let need = match size.checked_mul(mem::size_of::<T>()).and_then(Self::block_size) {
+            Some(need) => need,
+            None => return ptr::null_mut(),
+        };
+        let align = cmp::max(mem::align_of::<T>(), BLOCK_CTRL_ALIGNMENT);
+
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let block = unsafe { self.alloc_block(need, align) };
+        if block.is_null() {
+            ptr::null_mut()
+        } else {
+            (block as usize + BLOCK_CTRL_SIZE) as *mut T
+        }
     }
 
-    fn dealloc<T>(&mut self, ptr: *mut T, size: usize) {
+    fn dealloc<T>(&mut self, ptr: *mut T, _size: usize) {
+        if ptr.is_null() {
+            return
+        }
+
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let block = (ptr as usize - BLOCK_CTRL_SIZE) as *mut BlockCtrl;
+        unsafe {
+            debug_assert!((*block).is_allocated(), "double free");
+            self.allocate_size -= (*block).size;
+            self.free_block(block);
+        }
     }
 
-    fn realloc<T>(&mut self, ptr: *mut T, size: usize) -> *mut T{
-        ptr::null_mut()
+    fn realloc<T>(&mut self, ptr: *mut T, size: usize) -> *mut T {
+        if ptr.is_null() {
+            return self.alloc(size)
+        }
+        let need = match size.checked_mul(mem::size_of::<T>()).and_then(Self::block_size) {
+            Some(need) => need,
+            None => return ptr::null_mut(),
+        };
+        let align = cmp::max(mem::align_of::<T>(), BLOCK_CTRL_ALIGNMENT);
+
+        let _guard = lock_guard(unsafe { &mut *(&mut self.mutex as *mut M) });
+        let block = (ptr as usize - BLOCK_CTRL_SIZE) as *mut BlockCtrl;
+        unsafe {
+            let old = (*block).size;
+            if need <= old {
+                self.shrink_block(block, need);
+                return ptr
+            }
+            if self.expand_block(block, need) {
+                return ptr
+            }
+
+            let new = self.alloc_block(need, align);
+            if new.is_null() {
+                return ptr::null_mut()
+            }
+            let new_ptr = ((new as usize) + BLOCK_CTRL_SIZE) as *mut T;
+            ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, old - BLOCK_CTRL_SIZE);
+            self.allocate_size -= old;
+            self.free_block(block);
+            new_ptr
+        }
     }
 }
 
-// use super::*;
-// use ptr::{RawCell, RawPtr, OffsetPtr};
-// //use sync::Mutex;
-// use std::mem;
-// use std::ptr;
-// use libc;
-//
-//
-//
-// struct BlockCtrl {
-//     next: OffsetPtr<BlockCtrl>,
-//     free_size: usize,
-// }
-//
-// pub struct SimpleSeqFit {
-//     root: BlockCtrl,
-//     segment_bytes: usize,
-//     allocated_bytes: usize,
-// }
-//
-// impl SimpleSeqFit {
-//     fn dump(&self) {
-//         // let root = self.root.get();
-//         // let mut block = self.root.next.get();
-//         // print!("[  ");
-//         // while (block > root) {
-//         //     print!("offset:{} size:{}  ", block - root, block.size);
-//         //     assert!(block != block.next.get(), "block {} != block.next {}", block, block.next.get());
-//         //     block = block.next.get();
-//         // }
-//         // println!("]");
-//     }
-// }
-//
-// impl MemAlgo for SimpleSeqFit {
-//     fn init(&mut self, segment_bytes: usize) {
-//         self.segment_bytes = segment_bytes;
-//         self.allocated_bytes = 0;
-//         self.root.size = 0;
-//
-//         // let root = self.root.get();
-//         // let block1 = root.offset(mem::size_of::<Self>()).align::<BlockCtrl>();
-//         // self.root.next.set(block1);
-//         // self.root.next.next.set(root);
-//         // self.root.next.size = segment_bytes - (block1 - root);
-//     }
-//
-//     fn alloc<T>(&mut self, size: usize) -> *mut T {
-//         assert!(mem::align_of::<T>() <= BLOCK_CTRL_ALIGNMENT);
-//
-//         let root = self.root.get();
-//         let mut prev = root;
-//         let mut block = self.root.next.get();
-//         while block != root {
-//             let alloc_ptr = block.offset(BLOCK_CTRL_ALIGNMENT).align::<T>();
-//             let alloc_size = alloc_ptr + (mem::size_of::<T>() * size) - block;
-//             if alloc_size <= block.size {
-//                 let mut next = (block + alloc_size).align::<BlockCtrl>();
-//                 let remain_size = block.size - alloc_size;
-//                 prev.next.size = 0;
-//                 if remain_size > BLOCK_CTRL_ALIGNMENT {
-//                     prev.next.set(next);
-//                     prev.next.size = remain_size;
-//                     prev.next.next.set(block.next.get());
-//                 } else {
-//                     prev.next.set(block.next.get());
-//                 }
-//                 self.allocated_bytes += alloc_size;
-//                 return alloc_ptr.into();
-//             }
-//             prev = block;
-//             block = block.next.get();
-//         }
-//         ptr::null_mut()
-//     }
-//
-//     fn dealloc<T>(&mut self, ptr: *mut T, size: usize) {
-//         assert!(mem::align_of::<T>() <= BLOCK_CTRL_ALIGNMENT);
-//
-//         let ptr = (RawPtr::new(ptr) - BLOCK_CTRL_ALIGNMENT).align::<BlockCtrl>();
-//         let root = self.root.get();
-//         let mut prev = root;
-//         let mut block = self.root.next.get();
-//         while block != root {
-//             if prev < ptr && ptr < block {
-//                 let next = block.next.get();
-//                 prev.next.set(ptr);
-//                 prev.next.next.set(block);
-//                 prev.next.size = BLOCK_CTRL_ALIGNMENT + mem::size_of::<T>() * size;
-//                 return;
-//             }
-//             prev = block;
-//             block = block.next.get();
-//         }
-//     }
-//
-//     fn realloc<T>(&mut self, ptr: *mut T, size: usize) -> *mut T {
-//         ptr::null_mut()
-//     }
-// }
-//
-// // #[test]
-// // fn test_align() {
-// //     assert_eq!(align(1 as *const i32), 4 as *const i32);
-// //     assert_eq!(align(4 as *const i32), 4 as *const i32);
-// //     assert_eq!(align(100 as *const f64), 104 as *const f64);
-// //     assert_eq!(align(101 as *const f64), 104 as *const f64);
-// //
-// //     struct Align8 {
-// //         x: i32,
-// //         y: i64,
-// //         z: f64,
-// //     }
-// //     assert_eq!(align(100 as *const Align8), 104 as *const Align8);
-// //
-// //     struct Align4 {
-// //         x: [u8; 8],
-// //         y: i32,
-// //     }
-// //     assert_eq!(align(100 as *const Align4), 100 as *const Align4);
-// //
-// //     struct Align0;
-// //     assert_eq!(align(101 as *const Align0), 101 as *const Align0);
-// // }
-//
-// #[test]
-// fn test_alloc() {
-//     let base: &mut SimpleSeqFit = unsafe { &mut *(libc::malloc(4096) as *mut _) };
-//     base.init(4096);
-//     base.dump();
-//
-//     let x = base.alloc::<i32>(1);
-//     println!("x = {:p}", x);
-//     base.dump();
-//
-//     let y = base.alloc::<i32>(1);
-//     println!("y = {:p}", y);
-//     base.dump();
-//
-//     base.dealloc(y, 1);
-//     base.dump();
-//
-//     base.dealloc(x, 1);
-//     base.dump();
-//
-//     let a = base.alloc::<i32>(1000);
-//     base.dump();
-//
-//     unsafe { libc::free(base as *mut _ as *mut _) };
-// }
+#[test]
+fn test_alloc() {
+    use mem_algo::alloc_at_random;
+    use sync::NullMutex;
+
+    const SEGMENT_SIZE: usize = 64 * 1024;
+    let mut buf = vec![0u64; SEGMENT_SIZE / 8];
+    let base: &mut SimpleSeqFit<NullMutex> = unsafe { &mut *(buf.as_mut_ptr() as *mut _) };
+    base.place_new(SEGMENT_SIZE);
+    assert_eq!(base.check_integrity(), []);
+
+    alloc_at_random(base, 0x2545f4914f6cdd1d);
+    assert_eq!(base.allocate_size, 0);
+    assert_eq!(base.root.next.size, base.last_block() - base.first_block());
+    assert_eq!(base.stats().free_blocks, 1);
+    assert_eq!(base.stats().largest_free_block, base.get_free_memory());
+
+    // The new tail merges with the free block in front of it.
+    let ptr = base.alloc::<u8>(SEGMENT_SIZE / 2);
+    assert!(!ptr.is_null());
+    assert!(base.alloc::<u8>(SEGMENT_SIZE / 2).is_null());
+    buf.resize(SEGMENT_SIZE / 4, 0);
+    let base: &mut SimpleSeqFit<NullMutex> = unsafe { &mut *(buf.as_mut_ptr() as *mut _) };
+    base.grow(SEGMENT_SIZE * 2);
+    assert_eq!(base.check_integrity(), []);
+    let ptr = base.alloc::<u8>(SEGMENT_SIZE);
+    assert!(!ptr.is_null());
+    assert_eq!(base.check_integrity(), []);
+
+    let size = base.shrink_to_fit();
+    assert_eq!(base.check_integrity(), []);
+    assert_eq!(size, ptr as usize + SEGMENT_SIZE - base as *const _ as usize);
+    assert_eq!(base.shrink_to_fit(), size);
+    base.dealloc(ptr, SEGMENT_SIZE);
+    assert_eq!(base.check_integrity(), []);
+
+    // Corruption is reported instead of followed.
+    let ptr = base.alloc::<u8>(100);
+    let block = (ptr as usize - BLOCK_CTRL_SIZE) as *mut BlockCtrl;
+    let offset = block as usize - base as *const _ as usize;
+    base.allocate_size += 16;
+    assert_eq!(base.check_integrity(), [Problem::AllocatedSize { recorded: base.allocate_size, found: base.allocate_size - 16 }]);
+    base.allocate_size -= 16;
+    unsafe { (*block).size += 8 };
+    assert_eq!(base.check_integrity(), [Problem::BadBlockSize { offset: offset, size: unsafe { (*block).size } }]);
+}
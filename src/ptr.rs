@@ -69,3 +69,37 @@ impl<T> Pointer<T> for OffsetPtr<T> {
         }
     }
 }
+
+impl<T> OffsetPtr<T> {
+    /// An offset of 1 encodes null. Pointers to types aligned to more than a
+    /// byte never have it, and the keys and values that `RbtreeHook` points at
+    /// as bytes lie behind the whole hook, never 1 byte after the pointer field.
+    pub fn null() -> Self {
+        OffsetPtr {
+            offset: 1,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.offset == 1
+    }
+
+    pub fn get(&self) -> *mut T {
+        if self.is_null() {
+            ::std::ptr::null_mut()
+        } else {
+            (self as *const _ as isize).wrapping_add(self.offset) as *mut T
+        }
+    }
+
+    pub fn set(&mut self, ptr: *const T) {
+        self.offset = if ptr.is_null() {
+            1
+        } else {
+            let offset = (ptr as isize).wrapping_sub(self as *const _ as isize);
+            debug_assert!(offset != 1, "an offset of 1 cannot be told from null");
+            offset
+        };
+    }
+}
@@ -1,62 +1,1366 @@
-use sync::{Mutex, SharedMutex, PrivateMutex, NullMutex};
-use mem_algo::{MemAlgo, SimpleSeqFit};
-use indexes::Index;
-use SegmentManager;
+use sync::{Mutex, LockGuard, lock_guard};
+use mem_algo::{MemAlgo, MemStats, Problem, align_up};
+use indexes::{Index, fnv1a};
+use mapped_region::MappedRegion;
+use err::INVALID_ARGUMENT;
+use {SegmentManager, SharedType};
+use std::borrow::Cow;
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::io;
+use std::mem;
 use std::ptr;
+use std::slice;
+use std::vec;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-impl<I: Index> SegmentManager<SimpleSeqFit<SharedMutex>, I> {
-    pub fn construct<T>(&mut self, key: &str) -> Option<&mut T>
+/// Written last by `create`, so a segment carrying it is fully initialized.
+const SEGMENT_MAGIC: u64 = 0x4745_5343_5049_5352; // "RSIPCSEG"
+
+/// Bumped whenever the layout of segments changes.
+const FORMAT_VERSION: u32 = 2;
+
+/// 1 for little endian, 2 for big endian.
+const ENDIAN: u8 = if cfg!(target_endian = "little") { 1 } else { 2 };
+
+/// Placed at the start of the segment. The fields up to `layout` sit at the
+/// same offsets for every build, so they can be checked before anything else
+/// is touched. The memory algorithm comes last, because it manages
+/// everything behind itself.
+#[repr(C)]
+struct SegmentHeader<A: MemAlgo, I> {
+    magic: AtomicU64,
+    version: u32,
+    pointer_width: u8,
+    endian: u8,
+    layout: u64,
+    mutex: A::Mutex,
+    named_index: I,
+    unique_index: I,
+    algo: A,
+}
+
+/// Why a region was not accepted as a segment. Returned inside an
+/// `io::Error` of kind `InvalidData`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The region is zeroed, or its creator has not finished placing the segment yet.
+    Uninitialized,
+    /// The region does not hold a segment.
+    BadMagic,
+    /// The segment was created on a machine of the other byte order.
+    Endianness,
+    /// The segment was created by an incompatible version of this crate.
+    Version { found: u32, expected: u32 },
+    /// The segment was created by a build with another pointer size, in bits.
+    PointerWidth { found: u8, expected: u8 },
+    /// The segment was created with another memory algorithm, index or mutex.
+    Layout { found: u64, expected: u64 },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderError::Uninitialized => write!(f, "segment is not initialized"),
+            HeaderError::BadMagic => write!(f, "not a segment"),
+            HeaderError::Endianness => write!(f, "segment has the wrong byte order"),
+            HeaderError::Version { found, expected } =>
+                write!(f, "segment format version {}, expected {}", found, expected),
+            HeaderError::PointerWidth { found, expected } =>
+                write!(f, "segment pointer width {} bits, expected {}", found, expected),
+            HeaderError::Layout { found, expected } =>
+                write!(f, "segment layout {:016x}, expected {:016x}", found, expected),
+        }
+    }
+}
+
+impl error::Error for HeaderError {}
+
+impl From<HeaderError> for io::Error {
+    fn from(err: HeaderError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl HeaderError {
+    /// The `HeaderError` carried by `err`, if any.
+    pub fn from_io(err: &io::Error) -> Option<HeaderError> {
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<HeaderError>())
+            .cloned()
+    }
+}
+
+/// Fingerprint of the types a segment was created with. Segments are only
+/// opened with the same combination, and by builds that agree on its format.
+fn layout<A: MemAlgo, I: Index>() -> u64 {
+    fnv1a(format!("{} {:016x} {:016x} {:016x} {}",
+                  FORMAT_VERSION,
+                  type_fingerprint::<A>(),
+                  type_fingerprint::<I>(),
+                  type_fingerprint::<A::Mutex>(),
+                  mem::size_of::<SegmentHeader<A, I>>()).as_bytes())
+}
+
+/// How a block was constructed, which decides the index it is linked into.
+const NAMED: usize = 0;
+const UNIQUE: usize = 1;
+const ANONYMOUS: usize = 2;
+
+/// Adapt a constructor of a single value to the array interface.
+fn once<T, F>(func: F) -> impl FnMut(usize) -> Option<T>
+    where F: FnOnce() -> T
+{
+    let mut func = Some(func);
+    move |_| func.take().map(|func| func())
+}
+
+/// The first of `values`, or `None` for an empty array, which holds no `T`
+/// to refer to.
+fn first<T>(values: Option<*mut [T]>) -> Option<*mut T> {
+    values.filter(|values| !values.is_empty()).map(|values| values as *mut T)
+}
+
+/// Unique instances are keyed by the name of their type.
+fn unique_key<T: SharedType>() -> Cow<'static, str> {
+    if T::LEN == 1 {
+        Cow::Borrowed(T::NAME)
+    } else {
+        Cow::Owned(format!("[{}; {}]", T::NAME, T::LEN))
+    }
+}
+
+/// Identifies `T` in every process and build that opens a segment. Besides
+/// the name of the type, its size and alignment tell apart two binaries that
+/// disagree about a struct definition.
+pub fn type_fingerprint<T: SharedType>() -> u64 {
+    fnv1a(format!("{} {} {}",
+                  T::NAME,
+                  mem::size_of::<T>(),
+                  mem::align_of::<T>()).as_bytes())
+}
+
+/// An object was looked up as another type than it was constructed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeMismatch {
+    /// Fingerprint stored with the object.
+    pub found: u64,
+    /// Fingerprint of the type asked for.
+    pub expected: u64,
+}
+
+impl fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "object has type fingerprint {:016x}, expected {:016x}", self.found, self.expected)
+    }
+}
+
+impl error::Error for TypeMismatch {}
+
+impl From<TypeMismatch> for io::Error {
+    fn from(err: TypeMismatch) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Placed right in front of every constructed value. The allocation starts
+/// with the index hook and the name, followed by padding up to this header.
+#[repr(C)]
+struct BlockHeader {
+    fingerprint: u64,
+    name_offset: usize,
+    name_len: usize,
+    value_size: usize,
+    count: usize,
+    kind: usize,
+}
+
+impl BlockHeader {
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn name_ptr(&self) -> *mut u8 {
+        (self.addr() - self.name_offset) as *mut u8
+    }
+
+    fn name(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.name_ptr(), self.name_len) }
+    }
+
+    fn value<T>(&self) -> *mut T {
+        (self.addr() + mem::size_of::<Self>()) as *mut T
+    }
+
+    fn from_value<T>(value: *const T) -> *mut BlockHeader {
+        (value as usize - mem::size_of::<Self>()) as *mut BlockHeader
+    }
+
+    fn values<T>(&self) -> *mut [T] {
+        ptr::slice_from_raw_parts_mut(self.value::<T>(), self.count)
+    }
+
+    /// The values, if they were constructed as `T`.
+    fn typed_values<T: SharedType>(&self) -> Result<*mut [T], TypeMismatch> {
+        if self.fingerprint == type_fingerprint::<T>() {
+            Ok(self.values::<T>())
+        } else {
+            Err(TypeMismatch { found: self.fingerprint, expected: type_fingerprint::<T>() })
+        }
+    }
+}
+
+/// An object found by `named_iter` or `unique_iter`.
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    /// The name, or the type name of a unique instance.
+    pub name: String,
+    /// `type_fingerprint` of the type it was constructed as.
+    pub fingerprint: u64,
+    /// Number of elements, which is 1 unless it is an array.
+    pub count: usize,
+    /// Size of one element in bytes.
+    pub value_size: usize,
+    /// Address of the first element in this mapping.
+    pub addr: *const u8,
+    /// Offset of the first element from the start of the segment.
+    pub offset: usize,
+}
+
+/// The format of a segment, as recorded in its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub version: u32,
+    /// Size of a pointer in bits.
+    pub pointer_width: u8,
+    pub little_endian: bool,
+    /// Fingerprint of the memory algorithm, index and mutex types.
+    pub layout: u64,
+}
+
+/// Something wrong in a segment, found by `check_integrity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SegmentProblem {
+    /// A problem in the blocks of the memory algorithm.
+    Memory(Problem),
+    /// The length recorded by an index disagrees with its entries.
+    IndexLength { recorded: usize, found: usize },
+    /// The entry for `name` points outside the segment.
+    EntryOutOfBounds { name: String },
+    /// The entry for `name` does not point into an allocated block.
+    EntryNotAllocated { name: String },
+    /// The block of the entry for `name` has another name or kind, or its
+    /// values do not fit into it.
+    BadBlockHeader { name: String },
+}
+
+impl fmt::Display for SegmentProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SegmentProblem::Memory(ref problem) => problem.fmt(f),
+            SegmentProblem::IndexLength { recorded, found } =>
+                write!(f, "index records {} entries, but has {}", recorded, found),
+            SegmentProblem::EntryOutOfBounds { ref name } =>
+                write!(f, "entry {:?} points outside the segment", name),
+            SegmentProblem::EntryNotAllocated { ref name } =>
+                write!(f, "entry {:?} does not point into an allocated block", name),
+            SegmentProblem::BadBlockHeader { ref name } =>
+                write!(f, "entry {:?} has a bad block header", name),
+        }
+    }
+}
+
+/// Snapshot of the objects in an index, taken under the segment lock. It
+/// does not borrow the segment, so objects can be destroyed while iterating.
+pub struct ObjectIter {
+    objects: vec::IntoIter<ObjectInfo>,
+}
+
+impl Iterator for ObjectIter {
+    type Item = ObjectInfo;
+
+    fn next(&mut self) -> Option<ObjectInfo> {
+        self.objects.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.objects.size_hint()
+    }
+}
+
+impl ExactSizeIterator for ObjectIter {}
+
+/// Drops the elements constructed so far, then unlinks and frees a block
+/// whose construction panicked or failed.
+struct Rollback<'a, A: MemAlgo + 'a, I: Index + 'a, T> {
+    manager: &'a SegmentManager<A, I>,
+    block: *mut BlockHeader,
+    constructed: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, A: MemAlgo, I: Index, T> Drop for Rollback<'a, A, I, T> {
+    fn drop(&mut self) {
+        unsafe {
+            let value = (*self.block).value::<T>();
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(value, self.constructed));
+            self.manager.unlink_block(self.block);
+            self.manager.dealloc_block(self.block);
+        }
+    }
+}
+
+impl<A: MemAlgo, I: Index> SegmentManager<A, I> {
+    /// Place a new, empty segment at the start of `region`.
+    pub fn create(region: MappedRegion) -> io::Result<Self> {
+        if !Self::fits(&region) {
+            return Err(INVALID_ARGUMENT.into())
+        }
+
+        let manager: Self = SegmentManager {
+            region: region,
+            read_only: false,
+            _marker: PhantomData,
+        };
+        unsafe {
+            let header = &mut *manager.header();
+            header.version = FORMAT_VERSION;
+            header.pointer_width = (mem::size_of::<usize>() * 8) as u8;
+            header.endian = ENDIAN;
+            header.layout = layout::<A, I>();
+            header.mutex.place_new();
+            header.named_index.place_new();
+            header.unique_index.place_new();
+            header.algo.place_new(manager.algo_bytes());
+            header.magic.store(SEGMENT_MAGIC, Ordering::Release);
+        }
+        Ok(manager)
+    }
+
+    /// Attach to a segment created by `create`, possibly in another process.
+    pub fn open(region: MappedRegion) -> io::Result<Self> {
+        Self::attach(region, false)
+    }
+
+    /// Attach to a segment mapped without write access. Lookups do not lock
+    /// the segment, and everything that would modify it fails.
+    pub fn open_read_only(region: MappedRegion) -> io::Result<Self> {
+        Self::attach(region, true)
+    }
+
+    fn attach(region: MappedRegion, read_only: bool) -> io::Result<Self> {
+        if !Self::fits(&region) {
+            return Err(INVALID_ARGUMENT.into())
+        }
+
+        let manager: Self = SegmentManager {
+            region: region,
+            read_only: read_only,
+            _marker: PhantomData,
+        };
+        manager.check_header()?;
+        Ok(manager)
+    }
+
+    fn check_header(&self) -> Result<(), HeaderError> {
+        let header = unsafe { &*self.header() };
+        let magic = header.magic.load(Ordering::Acquire);
+        if magic == 0 {
+            return Err(HeaderError::Uninitialized)
+        }
+        if magic.swap_bytes() == SEGMENT_MAGIC {
+            return Err(HeaderError::Endianness)
+        }
+        if magic != SEGMENT_MAGIC {
+            return Err(HeaderError::BadMagic)
+        }
+        if header.version != FORMAT_VERSION {
+            return Err(HeaderError::Version { found: header.version, expected: FORMAT_VERSION })
+        }
+        let pointer_width = (mem::size_of::<usize>() * 8) as u8;
+        if header.pointer_width != pointer_width {
+            return Err(HeaderError::PointerWidth { found: header.pointer_width, expected: pointer_width })
+        }
+        if header.endian != ENDIAN {
+            return Err(HeaderError::Endianness)
+        }
+        if header.layout != layout::<A, I>() {
+            return Err(HeaderError::Layout { found: header.layout, expected: layout::<A, I>() })
+        }
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn header_info(&self) -> HeaderInfo {
+        let header = unsafe { &*self.header() };
+        HeaderInfo {
+            version: header.version,
+            pointer_width: header.pointer_width,
+            little_endian: header.endian == 1,
+            layout: header.layout,
+        }
+    }
+
+    /// Move over to `region`, which holds this segment followed by new bytes,
+    /// and hand those to the memory algorithm.
+    pub(crate) fn grow(&mut self, region: MappedRegion) {
+        self.region = region;
+        let _guard = self.lock();
+        unsafe { (*self.header()).algo.grow(self.algo_bytes()) };
+    }
+
+    /// Give the free memory at the end of the segment back and return the
+    /// bytes of the region still in use.
+    pub(crate) fn shrink_to_fit(&mut self) -> usize {
+        let _guard = self.lock();
+        unsafe {
+            let algo = &mut (*self.header()).algo;
+            algo as *const A as usize - self.region.base() as usize + algo.shrink_to_fit()
+        }
+    }
+
+    fn fits(region: &MappedRegion) -> bool {
+        let base = unsafe { region.base() } as usize;
+        let algo = unsafe { ptr::addr_of!((*(base as *const SegmentHeader<A, I>)).algo) } as usize;
+        region.size() >= mem::size_of::<SegmentHeader<A, I>>()
+            && base & (mem::align_of::<SegmentHeader<A, I>>() - 1) == 0
+            && region.size() - (algo - base) >= A::min_size(algo)
+    }
+
+    /// Bytes of the region managed by the memory algorithm.
+    fn algo_bytes(&self) -> usize {
+        unsafe {
+            let algo = &(*self.header()).algo as *const A as usize;
+            self.region.size() - (algo - self.region.base() as usize)
+        }
+    }
+
+    fn header(&self) -> *mut SegmentHeader<A, I> {
+        unsafe { self.region.base() as *mut SegmentHeader<A, I> }
+    }
+
+    /// A read-only mapping cannot take the mutex, so it goes without.
+    fn lock(&self) -> Option<LockGuard<'_, A::Mutex>> {
+        if self.read_only {
+            None
+        } else {
+            Some(lock_guard(unsafe { &mut (*self.header()).mutex }))
+        }
+    }
+
+    unsafe fn index(&self, kind: usize) -> Option<*mut I> {
+        let header = self.header();
+        match kind {
+            NAMED => Some(&mut (*header).named_index),
+            UNIQUE => Some(&mut (*header).unique_index),
+            _ => None,
+        }
+    }
+
+    unsafe fn find_block(&self, kind: usize, key: &str) -> Option<*mut BlockHeader> {
+        self.index(kind)
+            .and_then(|index| (*index).find(key.as_bytes()))
+            .map(|block| block as *mut BlockHeader)
+    }
+
+    unsafe fn unlink_block(&self, block: *mut BlockHeader) {
+        if let Some(index) = self.index((*block).kind) {
+            (*index).remove(&mut (*self.header()).algo, (*block).name());
+        }
+    }
+
+    fn hook(block: *mut BlockHeader) -> *mut I::Hook {
+        unsafe { ((*block).name_ptr() as usize - mem::size_of::<I::Hook>()) as *mut I::Hook }
+    }
+
+    /// Allocate a block holding `key` and `count` values of `T`.
+    unsafe fn alloc_block<T: SharedType>(&self, kind: usize, key: &str, count: usize) -> Option<*mut BlockHeader> {
+        assert!(mem::align_of::<I::Hook>() <= mem::align_of::<usize>());
+
+        let align = cmp::max(mem::align_of::<T>(), mem::align_of::<BlockHeader>());
+        let bytes = mem::size_of::<T>().checked_mul(count)?.checked_add(
+            mem::size_of::<I::Hook>()
+                + key.len()
+                + (align - 1)
+                + mem::size_of::<BlockHeader>())?;
+        let words = bytes.div_ceil(mem::size_of::<usize>());
+
+        let base = (*self.header()).algo.alloc::<usize>(words);
+        if base.is_null() {
+            return None
+        }
+
+        let name = base as usize + mem::size_of::<I::Hook>();
+        let value = align_up(name + key.len() + mem::size_of::<BlockHeader>(), align);
+        let block = (value - mem::size_of::<BlockHeader>()) as *mut BlockHeader;
+        ptr::copy_nonoverlapping(key.as_ptr(), name as *mut u8, key.len());
+        ptr::write(block, BlockHeader {
+            fingerprint: type_fingerprint::<T>(),
+            name_offset: block as usize - name,
+            name_len: key.len(),
+            value_size: mem::size_of::<T>(),
+            count: count,
+            kind: kind,
+        });
+        Some(block)
+    }
+
+    unsafe fn dealloc_block(&self, block: *mut BlockHeader) {
+        (*self.header()).algo.dealloc(Self::hook(block), 0);
+    }
+
+    /// Construct `count` values from `init`, rolling everything back if it
+    /// panics or returns `None`.
+    unsafe fn construct_block<T: SharedType, F>(&self, kind: usize, key: &str, count: usize, mut init: F) -> Option<*mut T>
+        where F: FnMut(usize) -> Option<T>
+    {
+        let block = self.alloc_block::<T>(kind, key, count)?;
+        if let Some(index) = self.index(kind) {
+            if !(*index).insert(&mut (*self.header()).algo, Self::hook(block), (*block).name(), block as *mut u8) {
+                self.dealloc_block(block);
+                return None
+            }
+        }
+
+        let mut rollback: Rollback<A, I, T> = Rollback {
+            manager: self,
+            block: block,
+            constructed: 0,
+            _marker: PhantomData,
+        };
+        let value = (*block).value::<T>();
+        while rollback.constructed < count {
+            ptr::write(value.add(rollback.constructed), init(rollback.constructed)?);
+            rollback.constructed += 1;
+        }
+        mem::forget(rollback);
+        Some(value)
+    }
+
+    /// Run the destructor of the value in `block` and give its memory back.
+    unsafe fn destroy_block<T: SharedType>(&self, block: *mut BlockHeader) -> bool {
+        if (*block).typed_values::<T>().is_err() {
+            return false
+        }
+
+        self.unlink_block(block);
+        ptr::drop_in_place((*block).values::<T>());
+        self.dealloc_block(block);
+        true
+    }
+
+    fn construct_kind<T: SharedType, F>(&self, kind: usize, key: &str, count: usize, init: F) -> Option<*mut [T]>
+        where F: FnMut(usize) -> Option<T>
+    {
+        if self.read_only {
+            return None
+        }
+        let _guard = self.lock();
+        unsafe {
+            if self.find_block(kind, key).is_some() {
+                return None
+            }
+            self.construct_block(kind, key, count, init)
+                .map(|value| ptr::slice_from_raw_parts_mut(value, count))
+        }
+    }
+
+    fn find_or_construct_kind<T: SharedType, F>(&self, kind: usize, key: &str, count: usize, init: F)
+                                    -> Result<Option<*mut [T]>, TypeMismatch>
+        where F: FnMut(usize) -> Option<T>
+    {
+        let _guard = self.lock();
+        unsafe {
+            if let Some(block) = self.find_block(kind, key) {
+                return (*block).typed_values::<T>().map(Some)
+            }
+            if self.read_only {
+                return Ok(None)
+            }
+            Ok(self.construct_block(kind, key, count, init)
+                .map(|value| ptr::slice_from_raw_parts_mut(value, count)))
+        }
+    }
+
+    fn find_kind<T: SharedType>(&self, kind: usize, key: &str) -> Result<Option<*mut [T]>, TypeMismatch> {
+        let _guard = self.lock();
+        unsafe {
+            match self.find_block(kind, key) {
+                Some(block) => (*block).typed_values::<T>().map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn destroy_kind<T: SharedType>(&self, kind: usize, key: &str) -> bool {
+        if self.read_only {
+            return false
+        }
+        let _guard = self.lock();
+        unsafe {
+            match self.find_block(kind, key) {
+                Some(block) => self.destroy_block::<T>(block),
+                None => false,
+            }
+        }
+    }
+
+    /// Construct a `T` named `key` in the segment, or `None` if the name is
+    /// taken or the segment is out of memory.
+    pub fn construct<T: SharedType, F>(&mut self, key: &str, func: F) -> Option<&mut T>
+        where F: FnOnce() -> T
     {
-        None
+        self.construct_kind(NAMED, key, 1, once(func)).map(|value| unsafe { &mut *(value as *mut T) })
     }
 
-    pub fn find_or_construct<T, F>(&mut self, key: &str, func: F) -> Option<&mut T>
+    /// Find the `T` named `key`, or construct it if there is none. Fails if
+    /// the object was constructed as another type.
+    pub fn find_or_construct<T: SharedType, F>(&mut self, key: &str, func: F) -> Result<Option<&mut T>, TypeMismatch>
+        where F: FnOnce() -> T
     {
-        None
+        self.find_or_construct_kind::<T, _>(NAMED, key, 1, once(func))
+            .map(|value| first(value).map(|value| unsafe { &mut *value }))
     }
 
-    pub fn find<T>(&self, key: &str) -> Option<&T> {
-        None
+    /// Find the `T` named `key`, or the first element if it is an array. Fails
+    /// if it was constructed as another type.
+    pub fn find<T: SharedType>(&self, key: &str) -> Result<Option<&T>, TypeMismatch> {
+        self.find_kind::<T>(NAMED, key).map(|value| first(value).map(|value| unsafe { &*value }))
     }
 
-    pub fn find_mut<T>(&mut self, key: &str) -> Option<&mut T> {
-        None
+    /// Like `find`, but finds nothing in a read-only segment.
+    pub fn find_mut<T: SharedType>(&mut self, key: &str) -> Result<Option<&mut T>, TypeMismatch> {
+        if self.read_only {
+            return Ok(None)
+        }
+        self.find_kind::<T>(NAMED, key).map(|value| first(value).map(|value| unsafe { &mut *value }))
     }
+
+    /// Construct `count` values named `key`, the `i`th one from `init(i)`.
+    pub fn construct_array<T: SharedType, F>(&mut self, key: &str, count: usize, mut init: F) -> Option<&mut [T]>
+        where F: FnMut(usize) -> T
+    {
+        self.construct_kind(NAMED, key, count, |i| Some(init(i))).map(|value| unsafe { &mut *value })
+    }
+
+    /// Construct an array named `key` holding the items of `iter`.
+    /// Nothing is kept if `iter` ends before its reported length.
+    pub fn construct_from_iter<T: SharedType, It>(&mut self, key: &str, iter: It) -> Option<&mut [T]>
+        where It: IntoIterator<Item = T>,
+              It::IntoIter: ExactSizeIterator
+    {
+        let mut iter = iter.into_iter();
+        let count = iter.len();
+        self.construct_kind(NAMED, key, count, |_| iter.next()).map(|value| unsafe { &mut *value })
+    }
+
+    pub fn find_array<T: SharedType>(&self, key: &str) -> Result<Option<&[T]>, TypeMismatch> {
+        self.find_kind::<T>(NAMED, key).map(|value| value.map(|value| unsafe { &*value }))
+    }
+
+    pub fn find_array_mut<T: SharedType>(&mut self, key: &str) -> Result<Option<&mut [T]>, TypeMismatch> {
+        if self.read_only {
+            return Ok(None)
+        }
+        self.find_kind::<T>(NAMED, key).map(|value| value.map(|value| unsafe { &mut *value }))
+    }
+
+    /// Drop the `T` named `key`, or all elements if it is an array, and free its memory.
+    pub fn destroy<T: SharedType>(&mut self, key: &str) -> bool {
+        self.destroy_kind::<T>(NAMED, key)
+    }
+
+    /// Construct the single instance of `T` in the segment, keyed by its type.
+    pub fn construct_unique<T: SharedType, F>(&mut self, func: F) -> Option<&mut T>
+        where F: FnOnce() -> T
+    {
+        self.construct_kind(UNIQUE, &unique_key::<T>(), 1, once(func)).map(|value| unsafe { &mut *(value as *mut T) })
+    }
+
+    pub fn find_or_construct_unique<T: SharedType, F>(&mut self, func: F) -> Result<Option<&mut T>, TypeMismatch>
+        where F: FnOnce() -> T
+    {
+        self.find_or_construct_kind::<T, _>(UNIQUE, &unique_key::<T>(), 1, once(func))
+            .map(|value| first(value).map(|value| unsafe { &mut *value }))
+    }
+
+    pub fn find_unique<T: SharedType>(&self) -> Result<Option<&T>, TypeMismatch> {
+        self.find_kind::<T>(UNIQUE, &unique_key::<T>()).map(|value| first(value).map(|value| unsafe { &*value }))
+    }
+
+    pub fn find_unique_mut<T: SharedType>(&mut self) -> Result<Option<&mut T>, TypeMismatch> {
+        if self.read_only {
+            return Ok(None)
+        }
+        self.find_kind::<T>(UNIQUE, &unique_key::<T>())
+            .map(|value| first(value).map(|value| unsafe { &mut *value }))
+    }
+
+    pub fn destroy_unique<T: SharedType>(&mut self) -> bool {
+        self.destroy_kind::<T>(UNIQUE, &unique_key::<T>())
+    }
+
+    /// Construct a `T` that no index knows about. It stays alive until it is
+    /// passed to `destroy_ptr`. Returns null if the segment is out of memory.
+    pub fn construct_anonymous<T: SharedType, F>(&mut self, func: F) -> *mut T
+        where F: FnOnce() -> T
+    {
+        if self.read_only {
+            return ptr::null_mut()
+        }
+        let _guard = self.lock();
+        unsafe { self.construct_block(ANONYMOUS, "", 1, once(func)) }.unwrap_or(ptr::null_mut())
+    }
+
+    fn objects(&self, kind: usize) -> ObjectIter {
+        let _guard = self.lock();
+        let objects = unsafe {
+            let index = &*self.index(kind).unwrap();
+            index.iter()
+                .map(|(_, block)| {
+                    let block = &*(block as *const BlockHeader);
+                    ObjectInfo {
+                        name: String::from_utf8_lossy(block.name()).into_owned(),
+                        fingerprint: block.fingerprint,
+                        count: block.count,
+                        value_size: block.value_size,
+                        addr: block.value::<u8>(),
+                        offset: block.value::<u8>() as usize - self.region.base() as usize,
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        ObjectIter { objects: objects.into_iter() }
+    }
+
+    /// Check the blocks of the memory algorithm, then every index entry
+    /// against the allocated blocks, and return what is wrong.
+    ///
+    /// After a process died inside the segment, run this on a read-only
+    /// mapping, because the segment lock may never be released.
+    pub fn check_integrity(&self) -> Vec<SegmentProblem> {
+        let _guard = self.lock();
+        unsafe {
+            let header = &*self.header();
+            let problems: Vec<_> = header.algo.check_integrity().into_iter().map(SegmentProblem::Memory).collect();
+            if !problems.is_empty() {
+                // Entries cannot be checked against blocks that are broken.
+                return problems
+            }
+
+            let mut allocated = Vec::new();
+            header.algo.allocated_blocks(|ptr, size| allocated.push((ptr as usize, size)));
+            let mut problems = Vec::new();
+            self.check_index(&header.named_index, NAMED, &allocated, &mut problems);
+            self.check_index(&header.unique_index, UNIQUE, &allocated, &mut problems);
+            problems
+        }
+    }
+
+    unsafe fn check_index(&self, index: &I, kind: usize, allocated: &[(usize, usize)],
+                          problems: &mut Vec<SegmentProblem>)
+    {
+        let base = self.region.base() as usize;
+        let end = base + self.region.size();
+        let in_segment = |addr: usize, len: usize| addr >= base && addr <= end && len <= end - addr;
+
+        let mut found = 0;
+        for (key, block) in index.iter() {
+            found += 1;
+            if !in_segment(key.as_ptr() as usize, key.len()) {
+                problems.push(SegmentProblem::EntryOutOfBounds { name: String::new() });
+                continue
+            }
+            let name = String::from_utf8_lossy(key).into_owned();
+            let block = block as usize;
+            if !in_segment(block, mem::size_of::<BlockHeader>())
+                || block & (mem::align_of::<BlockHeader>() - 1) != 0
+            {
+                problems.push(SegmentProblem::EntryOutOfBounds { name: name });
+                continue
+            }
+
+            // The allocation starts with the hook, in front of the name.
+            let header = &*(block as *const BlockHeader);
+            let start = block.wrapping_sub(header.name_offset).wrapping_sub(mem::size_of::<I::Hook>());
+            let size = match allocated.binary_search_by_key(&start, |&(ptr, _)| ptr) {
+                Ok(i) => allocated[i].1,
+                Err(_) => {
+                    problems.push(SegmentProblem::EntryNotAllocated { name: name });
+                    continue
+                },
+            };
+            let values = header.value_size.checked_mul(header.count)
+                .and_then(|bytes| bytes.checked_add(block + mem::size_of::<BlockHeader>()));
+            let name_fits = header.name_len == key.len() && mem::size_of::<I::Hook>() + key.len() <= size;
+            if header.kind != kind || !name_fits || header.name() != key
+                || values.is_none_or(|values| values > start + size)
+            {
+                problems.push(SegmentProblem::BadBlockHeader { name: name });
+            }
+        }
+        if found != index.len() {
+            problems.push(SegmentProblem::IndexLength { recorded: index.len(), found: found });
+        }
+    }
+
+    /// All named objects, in the order of the index.
+    pub fn named_iter(&self) -> ObjectIter {
+        self.objects(NAMED)
+    }
+
+    /// All unique instances, named by their type.
+    pub fn unique_iter(&self) -> ObjectIter {
+        self.objects(UNIQUE)
+    }
+
+    /// Number of elements constructed at `ptr`, which is 1 unless it is an array.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point at a value constructed in this segment.
+    pub unsafe fn instance_length<T>(&self, ptr: *const T) -> usize {
+        (*BlockHeader::from_value(ptr)).count
+    }
+
+    /// Drop a value returned by `construct`, `find` or `construct_anonymous`
+    /// and free its memory.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point at a value constructed in this segment.
+    pub unsafe fn destroy_ptr<T: SharedType>(&mut self, ptr: *const T) -> bool {
+        if self.read_only {
+            return false
+        }
+        let _guard = self.lock();
+        self.destroy_block::<T>(BlockHeader::from_value(ptr))
+    }
+
+    pub fn alloc<T>(&mut self, size: usize) -> *mut T {
+        if self.read_only {
+            return ptr::null_mut()
+        }
+        let _guard = self.lock();
+        unsafe { (*self.header()).algo.alloc(size) }
+    }
+
+    pub fn dealloc<T>(&mut self, ptr: *mut T, size: usize) {
+        if self.read_only {
+            return
+        }
+        let _guard = self.lock();
+        unsafe { (*self.header()).algo.dealloc(ptr, size) }
+    }
+
+    pub fn realloc<T>(&mut self, ptr: *mut T, size: usize) -> *mut T {
+        if self.read_only {
+            return ptr::null_mut()
+        }
+        let _guard = self.lock();
+        unsafe { (*self.header()).algo.realloc(ptr, size) }
+    }
+
+    /// Bytes of the whole segment, including its header.
+    pub fn get_size(&self) -> usize {
+        self.region.size()
+    }
+
+    pub fn get_free_memory(&self) -> usize {
+        let _guard = self.lock();
+        unsafe { (*self.header()).algo.get_free_memory() }
+    }
+
+    pub fn num_named_objects(&self) -> usize {
+        let _guard = self.lock();
+        unsafe { (*self.header()).named_index.len() }
+    }
+
+    pub fn num_unique_objects(&self) -> usize {
+        let _guard = self.lock();
+        unsafe { (*self.header()).unique_index.len() }
+    }
+
+    /// Call `func` with the offset from the start of the segment and the size
+    /// of every free block.
+    pub fn free_blocks<F>(&self, mut func: F)
+        where F: FnMut(usize, usize)
+    {
+        let _guard = self.lock();
+        let base = unsafe { self.region.base() } as usize;
+        unsafe { (*self.header()).algo.free_blocks(|ptr, size| func(ptr as usize - base, size)) }
+    }
+
+    /// Memory usage of the segment. `size` is that of the whole segment.
+    pub fn stats(&self) -> MemStats {
+        let _guard = self.lock();
+        let stats = unsafe { (*self.header()).algo.stats() };
+        MemStats { size: self.region.size(), ..stats }
+    }
+}
+
+#[test]
+fn test_construct_find() {
+    use sync::SharedMutex;
+    use mem_algo::{SimpleSeqFit, RbtreeBestFit};
+    use indexes::{FlatMapIndex, RbtreeIndex, HashIndex};
+    use mapped_region::shared_memory;
+    use std::process;
+
+    fn run<A: MemAlgo, I: Index>(name: &str) {
+        let region = shared_memory(name).size(64 * 1024).create().unwrap();
+        let mut segment = SegmentManager::<A, I>::create(region).unwrap();
+
+        assert_eq!(*segment.construct("answer", || 42u64).unwrap(), 42);
+        assert!(segment.construct("answer", || 0u64).is_none());
+        for i in 0..100 {
+            segment.construct(&format!("item{}", i), || [i as u32; 16]).unwrap();
+        }
+        *segment.find_or_construct("answer", || 0u64).unwrap().unwrap() += 1;
+        assert_eq!(*segment.find_or_construct("other", || 7u8).unwrap().unwrap(), 7);
+        // Names that leave the block header misaligned must not let values overrun.
+        for i in 1..9 {
+            segment.construct(&"x".repeat(i), || [i as u8; 100]).unwrap();
+        }
+
+        // A second mapping of the same segment lands at another address.
+        let other = SegmentManager::<A, I>::open(shared_memory(name).open().unwrap()).unwrap();
+        assert!(unsafe { other.region.base() != segment.region.base() });
+        assert_eq!(other.find::<u64>("answer").unwrap(), Some(&43));
+        assert_eq!(other.find::<u8>("other").unwrap(), Some(&7));
+        for i in 0..100 {
+            assert_eq!(other.find::<[u32; 16]>(&format!("item{}", i)).unwrap(), Some(&[i as u32; 16]));
+        }
+        for i in 1..9 {
+            assert_eq!(other.find::<[u8; 100]>(&"x".repeat(i)).unwrap(), Some(&[i as u8; 100]));
+        }
+        assert!(other.find::<u64>("missing").unwrap().is_none());
+
+        assert!(shared_memory(name).remove());
+    }
+
+    run::<SimpleSeqFit<SharedMutex>, FlatMapIndex>(&format!("/interprocess-test-segment-{}-1", process::id()));
+    run::<RbtreeBestFit<SharedMutex>, FlatMapIndex>(&format!("/interprocess-test-segment-{}-2", process::id()));
+    run::<RbtreeBestFit<SharedMutex>, RbtreeIndex>(&format!("/interprocess-test-segment-{}-3", process::id()));
+    run::<SimpleSeqFit<SharedMutex>, HashIndex>(&format!("/interprocess-test-segment-{}-4", process::id()));
 }
 
-impl<I: Index> SegmentManager<SimpleSeqFit<PrivateMutex>, I> {
-    pub fn construct<T>(&mut self, key: &str) -> Option<&mut T> {
-        None
+#[test]
+fn test_destroy() {
+    use sync::SharedMutex;
+    use mem_algo::RbtreeBestFit;
+    use indexes::FlatMapIndex;
+    use mapped_region::anon_shared_memory;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(u32);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
     }
+    unsafe impl SharedType for Counted {
+        const NAME: &'static str = "Counted";
+    }
+
+    let region = anon_shared_memory(64 * 1024).unwrap();
+    let mut segment = SegmentManager::<RbtreeBestFit<SharedMutex>, FlatMapIndex>::create(region).unwrap();
+
+    segment.construct("a", || Counted(1)).unwrap();
+    segment.construct("b", || Counted(2)).unwrap();
+    assert!(!segment.destroy::<u8>("a"));
+    assert!(segment.destroy::<Counted>("a"));
+    assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+    assert!(segment.find::<Counted>("a").unwrap().is_none());
+    assert!(!segment.destroy::<Counted>("a"));
+
+    let b = segment.find::<Counted>("b").unwrap().unwrap() as *const Counted;
+    assert!(unsafe { segment.destroy_ptr(b) });
+    assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+    assert!(segment.find::<Counted>("b").unwrap().is_none());
 
-    pub fn find<T>(&self, key: &str) -> Option<&T> {
-        None
+    // A panicking constructor leaves neither the name nor the memory behind.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        segment.construct::<Counted, _>("c", || panic!("constructor failed"));
+    }));
+    assert!(result.is_err());
+    assert!(segment.find::<Counted>("c").unwrap().is_none());
+    assert_eq!(segment.construct("c", || Counted(3)).unwrap().0, 3);
+    assert!(segment.destroy::<Counted>("c"));
+    assert_eq!(DROPS.load(Ordering::SeqCst), 3);
+
+    // Would run out of memory if destroy leaked.
+    for i in 0..4096 {
+        segment.construct("d", || [i; 64]).unwrap();
+        assert!(segment.destroy::<[i32; 64]>("d"));
     }
+}
+
+#[test]
+fn test_type_mismatch() {
+    use sync::SharedMutex;
+    use mem_algo::RbtreeBestFit;
+    use indexes::HashIndex;
+    use mapped_region::anon_shared_memory;
+
+    let region = anon_shared_memory(64 * 1024).unwrap();
+    let mut segment = SegmentManager::<RbtreeBestFit<SharedMutex>, HashIndex>::create(region).unwrap();
+
+    segment.construct("answer", || 42u64).unwrap();
+    segment.construct_array("table", 3, |i| i as u16).unwrap();
+
+    let mismatch = TypeMismatch { found: type_fingerprint::<u64>(), expected: type_fingerprint::<u32>() };
+    assert_eq!(segment.find::<u32>("answer"), Err(mismatch));
+    assert!(segment.find::<i64>("answer").is_err());
+    assert!(segment.find_array::<[u8; 8]>("answer").is_err());
+    assert!(segment.find_or_construct("answer", || 0u32).is_err());
+    assert!(!segment.destroy::<i64>("answer"));
+    assert_eq!(segment.find::<u64>("answer"), Ok(Some(&42)));
+    assert_eq!(segment.find::<u16>("table"), Ok(Some(&0)));
+    assert!(segment.find_array::<u32>("table").is_err());
+
+    segment.construct_unique(|| 1u8).unwrap();
+    assert_eq!(segment.find_unique::<u8>(), Ok(Some(&1)));
+    assert_eq!(segment.find_unique::<i8>(), Ok(None));
+}
+
+#[test]
+fn test_iter() {
+    use sync::SharedMutex;
+    use mem_algo::SimpleSeqFit;
+    use indexes::{Index, FlatMapIndex, RbtreeIndex, HashIndex};
+    use mapped_region::anon_shared_memory;
 
-    pub fn find_or_construct<T>(&mut self, key: &str) -> Option<&mut T> {
-        None
+    fn run<I: Index>() {
+        let region = anon_shared_memory(64 * 1024).unwrap();
+        let mut segment = SegmentManager::<SimpleSeqFit<SharedMutex>, I>::create(region).unwrap();
+
+        for i in 0..10 {
+            segment.construct(&format!("stale/{}", i), || i as u32).unwrap();
+        }
+        let table = segment.construct_array("table", 4, |i| i as u64).unwrap().as_ptr();
+        segment.construct_unique(|| 1.5f64).unwrap();
+        segment.construct_anonymous(|| 0u8);
+
+        let mut named: Vec<_> = segment.named_iter().collect();
+        named.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(named.len(), 11);
+        assert_eq!(named[0].name, "stale/0");
+        assert_eq!(named[0].fingerprint, type_fingerprint::<u32>());
+        assert_eq!(named[0].count, 1);
+        assert_eq!(named[0].value_size, 4);
+        assert_eq!(named[0].addr, segment.find::<u32>("stale/0").unwrap().unwrap() as *const u32 as *const u8);
+        assert_eq!(named[10].name, "table");
+        assert_eq!(named[10].count, 4);
+        assert_eq!(named[10].addr, table as *const u8);
+
+        let unique: Vec<_> = segment.unique_iter().collect();
+        assert_eq!(unique.len(), 1);
+        assert_eq!(unique[0].name, unique_key::<f64>());
+        assert_eq!(unique[0].fingerprint, type_fingerprint::<f64>());
+
+        // Remove everything under a prefix while iterating.
+        for object in segment.named_iter() {
+            if object.name.starts_with("stale/") {
+                assert!(segment.destroy::<u32>(&object.name));
+            }
+        }
+        let names: Vec<_> = segment.named_iter().map(|object| object.name).collect();
+        assert_eq!(names, ["table"]);
     }
 
-    pub fn find_mut<T>(&mut self, key: &str) -> Option<&mut T> {
-        None
+    run::<FlatMapIndex>();
+    run::<RbtreeIndex>();
+    run::<HashIndex>();
+}
+
+#[test]
+fn test_stats() {
+    use sync::SharedMutex;
+    use mem_algo::{SimpleSeqFit, RbtreeBestFit};
+    use indexes::FlatMapIndex;
+    use mapped_region::anon_shared_memory;
+
+    fn run<A: MemAlgo>() {
+        let region = anon_shared_memory(64 * 1024).unwrap();
+        let mut segment = SegmentManager::<A, FlatMapIndex>::create(region).unwrap();
+
+        let empty = segment.stats();
+        assert_eq!(segment.get_size(), 64 * 1024);
+        assert_eq!(empty.size, 64 * 1024);
+        assert_eq!(empty.free_memory, segment.get_free_memory());
+        assert!(empty.free_memory > 60 * 1024);
+        assert_eq!(empty.free_blocks, 1);
+        assert_eq!(empty.largest_free_block, empty.free_memory);
+        assert_eq!(empty.fragmentation(), 0.0);
+
+        for i in 0..20 {
+            segment.construct(&format!("item{}", i), || [0u8; 512]).unwrap();
+        }
+        segment.construct_unique(|| 0u64).unwrap();
+        assert_eq!(segment.num_named_objects(), 20);
+        assert_eq!(segment.num_unique_objects(), 1);
+        assert!(segment.get_free_memory() < empty.free_memory - 20 * 512);
+
+        // Every other object freed leaves holes in front of the large free tail.
+        for i in (0..20).step_by(2) {
+            assert!(segment.destroy::<[u8; 512]>(&format!("item{}", i)));
+        }
+        let stats = segment.stats();
+        assert_eq!(segment.num_named_objects(), 10);
+        assert!(stats.free_blocks > 1);
+        assert!(stats.largest_free_block < stats.free_memory);
+        assert!(stats.fragmentation() > 0.0 && stats.fragmentation() < 1.0);
+
+        let mut blocks = Vec::new();
+        segment.free_blocks(|offset, size| blocks.push((offset, size)));
+        assert_eq!(blocks.len(), stats.free_blocks);
+        assert_eq!(blocks.iter().map(|&(_, size)| size).sum::<usize>(), stats.free_memory);
+        assert!(blocks.iter().all(|&(offset, size)| offset + size <= stats.size));
     }
+
+    run::<SimpleSeqFit<SharedMutex>>();
+    run::<RbtreeBestFit<SharedMutex>>();
 }
 
-impl<I: Index> SegmentManager<SimpleSeqFit<NullMutex>, I> {
-    pub fn find<T>(&self, key: &str) -> Option<&T> {
-        None
+#[test]
+fn test_check_integrity() {
+    use sync::SharedMutex;
+    use mem_algo::{SimpleSeqFit, RbtreeBestFit};
+    use indexes::{Index, FlatMapIndex, RbtreeIndex, HashIndex};
+    use mapped_region::anon_shared_memory;
+
+    fn run<A: MemAlgo, I: Index>() {
+        let region = anon_shared_memory(64 * 1024).unwrap();
+        let mut segment = SegmentManager::<A, I>::create(region).unwrap();
+        assert_eq!(segment.check_integrity(), []);
+
+        for i in 0..50 {
+            segment.construct_array(&format!("item{}", i), i, |j| j as u32).unwrap();
+        }
+        segment.construct_unique(|| 0u64).unwrap();
+        for i in 0..25 {
+            assert!(segment.destroy::<u32>(&format!("item{}", i * 2)));
+        }
+        assert_eq!(segment.check_integrity(), []);
+
+        let name = String::from("item7");
+        let block = unsafe { &mut *BlockHeader::from_value(segment.find_array::<u32>(&name).unwrap().unwrap().as_ptr()) };
+        block.kind = UNIQUE;
+        assert_eq!(segment.check_integrity(), [SegmentProblem::BadBlockHeader { name: name.clone() }]);
+        block.kind = NAMED;
+        block.count = 1 << 20;
+        assert_eq!(segment.check_integrity(), [SegmentProblem::BadBlockHeader { name: name.clone() }]);
+        block.count = 7;
+        block.name_offset += 16;
+        assert_eq!(segment.check_integrity(), [SegmentProblem::EntryNotAllocated { name: name.clone() }]);
+        block.name_offset -= 16;
+        assert_eq!(segment.check_integrity(), []);
     }
+
+    run::<SimpleSeqFit<SharedMutex>, FlatMapIndex>();
+    run::<RbtreeBestFit<SharedMutex>, RbtreeIndex>();
+    run::<RbtreeBestFit<SharedMutex>, HashIndex>();
 }
 
-impl<M: Mutex, I: Index> MemAlgo for SegmentManager<SimpleSeqFit<M>, I> {
-    fn alloc<T>(&mut self, size: usize) -> *mut T {
-        ptr::null_mut()
+#[test]
+fn test_unique_anonymous() {
+    use sync::SharedMutex;
+    use mem_algo::SimpleSeqFit;
+    use indexes::FlatMapIndex;
+    use mapped_region::anon_shared_memory;
+
+    struct Config {
+        version: u32,
     }
+    unsafe impl SharedType for Config {
+        const NAME: &'static str = "Config";
+    }
+
+    let region = anon_shared_memory(64 * 1024).unwrap();
+    let mut segment = SegmentManager::<SimpleSeqFit<SharedMutex>, FlatMapIndex>::create(region).unwrap();
+
+    assert_eq!(segment.construct_unique(|| Config { version: 1 }).unwrap().version, 1);
+    assert!(segment.construct_unique(|| Config { version: 2 }).is_none());
+    segment.find_unique_mut::<Config>().unwrap().unwrap().version = 3;
+    assert_eq!(segment.find_or_construct_unique(|| Config { version: 4 }).unwrap().unwrap().version, 3);
+    assert_eq!(*segment.find_or_construct_unique(|| 5u64).unwrap().unwrap(), 5);
+    segment.construct_unique(|| [1u32; 2]).unwrap();
+    segment.construct_unique(|| [2u32; 3]).unwrap();
+    assert_eq!(segment.find_unique::<[u32; 2]>().unwrap(), Some(&[1; 2]));
+    assert_eq!(unique_key::<[[u32; 3]; 2]>(), "[u32; 6]");
+
+    // Unique instances do not show up as named ones.
+    assert!(segment.find::<Config>(&unique_key::<Config>()).unwrap().is_none());
+    segment.construct(&unique_key::<Config>(), || Config { version: 6 }).unwrap();
+    assert_eq!(segment.find_unique::<Config>().unwrap().unwrap().version, 3);
+
+    let node = segment.construct_anonymous(|| [7u8; 100]);
+    assert!(!node.is_null());
+    assert_eq!(unsafe { (*node)[99] }, 7);
+    assert!(segment.find::<[u8; 100]>("").unwrap().is_none());
+    assert!(unsafe { segment.destroy_ptr(node) });
+
+    assert!(segment.destroy_unique::<Config>());
+    assert!(segment.find_unique::<Config>().unwrap().is_none());
+    assert!(segment.find::<Config>(&unique_key::<Config>()).unwrap().is_some());
+}
+
+#[test]
+fn test_array() {
+    use sync::SharedMutex;
+    use mem_algo::RbtreeBestFit;
+    use indexes::FlatMapIndex;
+    use mapped_region::anon_shared_memory;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::rc::Rc;
+
+    #[derive(Debug, PartialEq)]
+    struct Sensor {
+        id: u32,
+        value: f64,
+    }
+    unsafe impl SharedType for Sensor {
+        const NAME: &'static str = "Sensor";
+    }
+
+    /// Tells how many clones of the `Rc` are alive.
+    struct Counter(Rc<()>);
+    unsafe impl SharedType for Counter {
+        const NAME: &'static str = "Counter";
+    }
+
+    let region = anon_shared_memory(64 * 1024).unwrap();
+    let mut segment = SegmentManager::<RbtreeBestFit<SharedMutex>, FlatMapIndex>::create(region).unwrap();
+
+    let sensors = segment.construct_array("sensors", 10, |i| Sensor { id: i as u32, value: 0.5 }).unwrap();
+    assert_eq!(sensors.len(), 10);
+    let ptr = sensors.as_ptr();
+    assert_eq!(unsafe { segment.instance_length(ptr) }, 10);
+    assert_eq!(segment.find_array::<Sensor>("sensors").unwrap().unwrap()[9], Sensor { id: 9, value: 0.5 });
+    segment.find_array_mut::<Sensor>("sensors").unwrap().unwrap()[3].value = 2.0;
+    assert_eq!(segment.find::<Sensor>("sensors").unwrap().unwrap().id, 0);
+    assert_eq!(segment.find_array::<Sensor>("sensors").unwrap().unwrap()[3].value, 2.0);
+
+    let squares = segment.construct_from_iter("squares", (0..5u32).map(|i| i as u64 * i as u64)).unwrap();
+    assert_eq!(squares, &[0, 1, 4, 9, 16]);
+    assert!(segment.construct_array("squares", 1, |_| 0u64).is_none());
+    assert_eq!(segment.construct_array::<u8, _>("empty", 0, |_| unreachable!()).unwrap().len(), 0);
+    // An empty array has no element to refer to.
+    assert!(segment.find::<u8>("empty").unwrap().is_none());
+    assert!(segment.find_mut::<u8>("empty").unwrap().is_none());
+    assert!(segment.find_or_construct("empty", || 1u8).unwrap().is_none());
+    assert_eq!(segment.find_array::<u8>("empty").unwrap().unwrap().len(), 0);
+    segment.construct_from_iter("no squares", Vec::<u64>::new()).unwrap();
+    assert!(segment.find::<u64>("no squares").unwrap().is_none());
+
+    let single = segment.construct("single", || 1u16).unwrap() as *const u16;
+    assert_eq!(unsafe { segment.instance_length(single) }, 1);
 
-    fn dealloc<T>(&mut self, ptr: *mut T, size: usize) {
+    // Elements built before a panic are dropped again.
+    let counter = Rc::new(());
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        segment.construct_array("partial", 4, |i| {
+            if i == 3 {
+                panic!("element failed");
+            }
+            Counter(counter.clone())
+        });
+    }));
+    assert!(result.is_err());
+    assert_eq!(Rc::strong_count(&counter), 1);
+    assert!(segment.find_array::<Counter>("partial").unwrap().is_none());
+
+    let rcs = segment.construct_array("rcs", 5, |_| Counter(counter.clone())).unwrap().as_ptr();
+    assert_eq!(Rc::strong_count(&counter), 6);
+    assert!(Rc::ptr_eq(&segment.find_array::<Counter>("rcs").unwrap().unwrap()[4].0, &counter));
+    assert!(unsafe { segment.destroy_ptr(rcs) });
+    assert_eq!(Rc::strong_count(&counter), 1);
+    assert!(segment.destroy::<Sensor>("sensors"));
+    assert!(segment.find_array::<Sensor>("sensors").unwrap().is_none());
+}
+
+#[test]
+fn test_too_small() {
+    use sync::NullMutex;
+    use mem_algo::{SimpleSeqFit, RbtreeBestFit};
+    use indexes::FlatMapIndex;
+
+    fn run<A: MemAlgo>() {
+        let mut buffer = vec![0u64; 512];
+        let base = buffer.as_mut_ptr() as usize;
+        let algo = unsafe { ptr::addr_of!((*(base as *const SegmentHeader<A, FlatMapIndex>)).algo) } as usize;
+        let min = algo - base + A::min_size(algo);
+        for &size in [mem::size_of::<SegmentHeader<A, FlatMapIndex>>(), min - 1, min].iter() {
+            let region = unsafe { MappedRegion::from_raw_parts(base as *mut _, size) };
+            let err = SegmentManager::<A, FlatMapIndex>::create(region).err();
+            assert_eq!(err.map(|err| err.kind()), if size < min { Some(io::ErrorKind::InvalidInput) } else { None });
+        }
     }
 
-    fn realloc<T>(&mut self, ptr: *mut T, size: usize) -> *mut T {
-        ptr::null_mut()
+    run::<SimpleSeqFit<NullMutex>>();
+    run::<RbtreeBestFit<NullMutex>>();
+}
+
+#[test]
+fn test_header() {
+    use sync::NullMutex;
+    use mem_algo::{SimpleSeqFit, RbtreeBestFit};
+    use indexes::{RbtreeIndex, HashIndex};
+    use managed_heap_memory::BasicManagedHeapMemory;
+
+    type Heap = BasicManagedHeapMemory<RbtreeBestFit<NullMutex>, RbtreeIndex>;
+
+    fn error<A: MemAlgo, I: Index>(bytes: &[u8]) -> Option<HeaderError> {
+        HeaderError::from_io(&BasicManagedHeapMemory::<A, I>::from_bytes(bytes).err().unwrap())
     }
+
+    let mut heap = Heap::new(4096).unwrap();
+    heap.construct("answer", || 42u32).unwrap();
+    let bytes = heap.as_bytes().to_vec();
+    assert_eq!(Heap::from_bytes(&bytes).unwrap().find::<u32>("answer").unwrap(), Some(&42));
+    assert_eq!(heap.header_info(), HeaderInfo {
+        version: FORMAT_VERSION,
+        pointer_width: (mem::size_of::<usize>() * 8) as u8,
+        little_endian: cfg!(target_endian = "little"),
+        layout: layout::<RbtreeBestFit<NullMutex>, RbtreeIndex>(),
+    });
+
+    assert!(layout::<SimpleSeqFit<NullMutex>, RbtreeIndex>() != layout::<RbtreeBestFit<NullMutex>, RbtreeIndex>());
+    match error::<SimpleSeqFit<NullMutex>, RbtreeIndex>(&bytes) {
+        Some(HeaderError::Layout { .. }) => {},
+        err => panic!("{:?}", err),
+    }
+    match error::<RbtreeBestFit<NullMutex>, HashIndex>(&bytes) {
+        Some(HeaderError::Layout { .. }) => {},
+        err => panic!("{:?}", err),
+    }
+
+    let mut copy = bytes.clone();
+    copy[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_ne_bytes());
+    assert_eq!(error::<RbtreeBestFit<NullMutex>, RbtreeIndex>(&copy),
+               Some(HeaderError::Version { found: FORMAT_VERSION + 1, expected: FORMAT_VERSION }));
+
+    let mut copy = bytes.clone();
+    copy[12] = 16;
+    assert_eq!(error::<RbtreeBestFit<NullMutex>, RbtreeIndex>(&copy),
+               Some(HeaderError::PointerWidth { found: 16, expected: (mem::size_of::<usize>() * 8) as u8 }));
+
+    let mut copy = bytes.clone();
+    copy[..8].reverse();
+    assert_eq!(error::<RbtreeBestFit<NullMutex>, RbtreeIndex>(&copy), Some(HeaderError::Endianness));
+
+    let mut copy = bytes.clone();
+    copy[0] ^= 1;
+    assert_eq!(error::<RbtreeBestFit<NullMutex>, RbtreeIndex>(&copy), Some(HeaderError::BadMagic));
+
+    assert_eq!(error::<RbtreeBestFit<NullMutex>, RbtreeIndex>(&[0; 4096]), Some(HeaderError::Uninitialized));
 }
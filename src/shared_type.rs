@@ -0,0 +1,34 @@
+/// A type that can be constructed in a segment. `NAME` identifies it in
+/// every process that opens the segment, whichever compiler built it, so it
+/// is written out by hand instead of taken from `std::any::type_name`.
+///
+/// Arrays take the name of their element type, and are told apart by their
+/// `LEN`.
+///
+/// Memory algorithms, indexes and mutexes implement it too, because the
+/// segment header records which ones a segment was created with.
+///
+/// # Safety
+///
+/// Types with the same `NAME`, size and alignment must have the same layout,
+/// because an object constructed as one of them can be found as another.
+pub unsafe trait SharedType {
+    const NAME: &'static str;
+    /// Number of `NAME` values in a `Self`, which is only more than 1 for arrays.
+    const LEN: usize = 1;
+}
+
+macro_rules! shared_types {
+    ($($ty:ident)*) => {
+        $(unsafe impl SharedType for $ty {
+            const NAME: &'static str = stringify!($ty);
+        })*
+    }
+}
+
+shared_types!(u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize f32 f64 bool char);
+
+unsafe impl<T: SharedType, const N: usize> SharedType for [T; N] {
+    const NAME: &'static str = T::NAME;
+    const LEN: usize = N * T::LEN;
+}
@@ -1,4 +1,6 @@
-pub trait Mutex {
+use SharedType;
+
+pub trait Mutex: SharedType {
     fn place_new(&mut self);
 
     fn lock(&mut self);
@@ -28,3 +30,9 @@ mod posix;
 
 #[cfg(unix)]
 pub use self::posix::*;
+
+#[cfg(unix)]
+mod upgradable_mutex;
+
+#[cfg(unix)]
+pub use self::upgradable_mutex::*;
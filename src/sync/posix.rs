@@ -1,11 +1,20 @@
-use sync::Mutex;
+use sync::{Mutex, LockGuard};
+use err::{ErrCode, STATE_NOT_RECOVERABLE};
+use std::cell::UnsafeCell;
+use std::io;
 use std::mem;
+use std::time::Duration;
 use libc;
+use SharedType;
 
 pub struct NullMutex {
     _mutex: libc::pthread_mutex_t,
 }
 
+unsafe impl SharedType for NullMutex {
+    const NAME: &'static str = "NullMutex";
+}
+
 impl Mutex for NullMutex {
     fn place_new(&mut self) {}
     fn lock(&mut self) {}
@@ -18,12 +27,18 @@ pub struct SharedMutex {
     mutex: libc::pthread_mutex_t,
 }
 
+unsafe impl SharedType for SharedMutex {
+    const NAME: &'static str = "SharedMutex";
+}
+
 impl Mutex for SharedMutex {
     fn place_new(&mut self) {
         unsafe {
             let mut attr: libc::pthread_mutexattr_t = mem::uninitialized();
+            libc::pthread_mutexattr_init(&mut attr);
             libc::pthread_mutexattr_setpshared(&mut attr, libc::PTHREAD_PROCESS_SHARED);
             libc::pthread_mutex_init(&mut self.mutex, &attr);
+            libc::pthread_mutexattr_destroy(&mut attr);
         }
     }
 
@@ -41,16 +56,58 @@ impl Mutex for SharedMutex {
 }
 
 
+/// Mutex shared between processes, which the owning thread may lock again.
+/// It is released once unlocked as often as it was locked.
+pub struct SharedRecursiveMutex {
+    mutex: libc::pthread_mutex_t,
+}
+
+unsafe impl SharedType for SharedRecursiveMutex {
+    const NAME: &'static str = "SharedRecursiveMutex";
+}
+
+impl Mutex for SharedRecursiveMutex {
+    fn place_new(&mut self) {
+        unsafe {
+            let mut attr: libc::pthread_mutexattr_t = mem::zeroed();
+            libc::pthread_mutexattr_init(&mut attr);
+            libc::pthread_mutexattr_setpshared(&mut attr, libc::PTHREAD_PROCESS_SHARED);
+            libc::pthread_mutexattr_settype(&mut attr, libc::PTHREAD_MUTEX_RECURSIVE);
+            libc::pthread_mutex_init(&mut self.mutex, &attr);
+            libc::pthread_mutexattr_destroy(&mut attr);
+        }
+    }
+
+    fn lock(&mut self) {
+        unsafe { libc::pthread_mutex_lock(&mut self.mutex) };
+    }
+
+    fn try_lock(&mut self) -> bool {
+        unsafe { libc::pthread_mutex_trylock(&mut self.mutex) == 0 }
+    }
+
+    fn unlock(&mut self) {
+        unsafe { libc::pthread_mutex_unlock(&mut self.mutex) };
+    }
+}
+
+
 pub struct PrivateMutex {
     mutex: libc::pthread_mutex_t,
 }
 
+unsafe impl SharedType for PrivateMutex {
+    const NAME: &'static str = "PrivateMutex";
+}
+
 impl Mutex for PrivateMutex {
     fn place_new(&mut self) {
         unsafe {
             let mut attr: libc::pthread_mutexattr_t = mem::uninitialized();
+            libc::pthread_mutexattr_init(&mut attr);
             libc::pthread_mutexattr_setpshared(&mut attr, libc::PTHREAD_PROCESS_PRIVATE);
             libc::pthread_mutex_init(&mut self.mutex, &attr);
+            libc::pthread_mutexattr_destroy(&mut attr);
         }
     }
 
@@ -66,3 +123,495 @@ impl Mutex for PrivateMutex {
         unsafe { libc::pthread_mutex_unlock(&mut self.mutex) };
     }
 }
+
+
+/// Condition variable shared between processes. Place it in a mapped region
+/// next to the `SharedMutex` that protects the condition. It is waited on
+/// and notified through `&self`, so threads of one process can share it.
+pub struct Condvar {
+    cond: UnsafeCell<libc::pthread_cond_t>,
+}
+
+unsafe impl Sync for Condvar {}
+
+impl Condvar {
+    pub fn place_new(&mut self) {
+        unsafe {
+            let mut attr: libc::pthread_condattr_t = mem::zeroed();
+            libc::pthread_condattr_init(&mut attr);
+            libc::pthread_condattr_setpshared(&mut attr, libc::PTHREAD_PROCESS_SHARED);
+            #[cfg(not(target_os = "macos"))]
+            libc::pthread_condattr_setclock(&mut attr, libc::CLOCK_MONOTONIC);
+            libc::pthread_cond_init(self.cond.get(), &attr);
+            libc::pthread_condattr_destroy(&mut attr);
+        }
+    }
+
+    /// Unlock the mutex, block until notified and lock it again. Wakeups
+    /// may be spurious, so check the condition in a loop, or use `wait_while`.
+    pub fn wait<'a>(&self, guard: LockGuard<'a, SharedMutex>) -> LockGuard<'a, SharedMutex> {
+        unsafe { libc::pthread_cond_wait(self.cond.get(), &mut guard.0.mutex) };
+        guard
+    }
+
+    /// Block as long as `condition` returns true.
+    pub fn wait_while<'a, F>(&self, mut guard: LockGuard<'a, SharedMutex>, mut condition: F)
+                             -> LockGuard<'a, SharedMutex>
+        where F: FnMut() -> bool
+    {
+        while condition() {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Like `wait`, but give up after `dur`. Also returns whether it timed out.
+    pub fn wait_timeout<'a>(&self, guard: LockGuard<'a, SharedMutex>, dur: Duration)
+                            -> (LockGuard<'a, SharedMutex>, bool)
+    {
+        let deadline = deadline(dur);
+        let res = unsafe { libc::pthread_cond_timedwait(self.cond.get(), &mut guard.0.mutex, &deadline) };
+        (guard, res == libc::ETIMEDOUT)
+    }
+
+    /// Wake up one of the waiting threads, in any process.
+    pub fn notify_one(&self) {
+        unsafe { libc::pthread_cond_signal(self.cond.get()) };
+    }
+
+    /// Wake up all waiting threads, in every process.
+    pub fn notify_all(&self) {
+        unsafe { libc::pthread_cond_broadcast(self.cond.get()) };
+    }
+}
+
+/// `dur` from now on the clock of `Condvar`, as `pthread_cond_timedwait` expects.
+fn deadline(dur: Duration) -> libc::timespec {
+    use std::convert::TryInto;
+
+    #[cfg(not(target_os = "macos"))]
+    const CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC;
+    #[cfg(target_os = "macos")]
+    const CLOCK: libc::clockid_t = libc::CLOCK_REALTIME;
+
+    let mut deadline: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(CLOCK, &mut deadline) };
+    let nsec = deadline.tv_nsec as u64 + dur.subsec_nanos() as u64;
+    let sec = (dur.as_secs() + nsec / 1_000_000_000)
+        .try_into().ok()
+        .and_then(|secs: libc::time_t| deadline.tv_sec.checked_add(secs));
+    match sec {
+        Some(sec) => {
+            deadline.tv_sec = sec;
+            deadline.tv_nsec = (nsec % 1_000_000_000) as _;
+        },
+        None => {
+            deadline.tv_sec = libc::time_t::MAX;
+            deadline.tv_nsec = 999_999_999;
+        },
+    }
+    deadline
+}
+
+/// Mutex shared between processes, which is handed to the next process
+/// locking it when its owner dies, instead of staying locked forever.
+pub struct RobustMutex {
+    mutex: libc::pthread_mutex_t,
+}
+
+/// How `RobustMutex::lock` acquired the mutex.
+pub enum RobustLock<'a> {
+    Locked(RobustGuard<'a>),
+    /// The previous owner died holding the mutex, so the state it protects
+    /// may be half updated. Repair it and call `consistent` on the guard.
+    /// Unlocking without doing so makes the mutex unrecoverable.
+    OwnerDied(RobustGuard<'a>),
+}
+
+impl<'a> RobustLock<'a> {
+    pub fn owner_died(&self) -> bool {
+        match *self {
+            RobustLock::Locked(_) => false,
+            RobustLock::OwnerDied(_) => true,
+        }
+    }
+
+    pub fn into_guard(self) -> RobustGuard<'a> {
+        match self {
+            RobustLock::Locked(guard) | RobustLock::OwnerDied(guard) => guard,
+        }
+    }
+}
+
+/// Unlocks the `RobustMutex` when dropped.
+pub struct RobustGuard<'a>(&'a mut RobustMutex);
+
+impl<'a> RobustGuard<'a> {
+    /// Declare the state repaired after `RobustLock::OwnerDied`, so the
+    /// mutex keeps working once unlocked.
+    pub fn consistent(&mut self) -> io::Result<()> {
+        match unsafe { libc::pthread_mutex_consistent(&mut self.0.mutex) } {
+            0 => Ok(()),
+            err => Err(ErrCode::new(err).into()),
+        }
+    }
+
+    /// Unlock without repairing the state after `RobustLock::OwnerDied`.
+    /// Every later attempt to lock the mutex fails.
+    pub fn mark_unrecoverable(self) {}
+}
+
+impl<'a> Drop for RobustGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_mutex_unlock(&mut self.0.mutex) };
+    }
+}
+
+impl RobustMutex {
+    pub fn place_new(&mut self) {
+        unsafe {
+            let mut attr: libc::pthread_mutexattr_t = mem::zeroed();
+            libc::pthread_mutexattr_init(&mut attr);
+            libc::pthread_mutexattr_setpshared(&mut attr, libc::PTHREAD_PROCESS_SHARED);
+            libc::pthread_mutexattr_setrobust(&mut attr, libc::PTHREAD_MUTEX_ROBUST);
+            libc::pthread_mutex_init(&mut self.mutex, &attr);
+            libc::pthread_mutexattr_destroy(&mut attr);
+        }
+    }
+
+    /// Fails with `ENOTRECOVERABLE` once an owner died and the next one
+    /// did not make the mutex consistent.
+    pub fn lock(&mut self) -> io::Result<RobustLock<'_>> {
+        let res = unsafe { libc::pthread_mutex_lock(&mut self.mutex) };
+        self.acquired(res)
+    }
+
+    /// Like `lock`, but returns `None` instead of blocking.
+    pub fn try_lock(&mut self) -> io::Result<Option<RobustLock<'_>>> {
+        match unsafe { libc::pthread_mutex_trylock(&mut self.mutex) } {
+            libc::EBUSY => Ok(None),
+            res => self.acquired(res).map(Some),
+        }
+    }
+
+    fn acquired(&mut self, res: i32) -> io::Result<RobustLock<'_>> {
+        match res {
+            0 => Ok(RobustLock::Locked(RobustGuard(self))),
+            libc::EOWNERDEAD => Ok(RobustLock::OwnerDied(RobustGuard(self))),
+            libc::ENOTRECOVERABLE => Err(STATE_NOT_RECOVERABLE.into()),
+            err => Err(ErrCode::new(err).into()),
+        }
+    }
+}
+
+/// Reader-writer lock shared between processes. It is taken through `&self`,
+/// so threads of one process can read at the same time.
+pub struct SharedRwLock {
+    rwlock: UnsafeCell<libc::pthread_rwlock_t>,
+}
+
+unsafe impl Sync for SharedRwLock {}
+
+/// Not exported by libc. Writers waiting for the lock block new readers.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+const PTHREAD_RWLOCK_PREFER_WRITER_NONRECURSIVE_NP: libc::c_int = 2;
+
+impl SharedRwLock {
+    /// Readers may keep acquiring the lock while a writer waits for it,
+    /// which can starve the writer.
+    pub fn place_new(&mut self) {
+        self.init(false)
+    }
+
+    /// Once a writer waits, new readers wait behind it. Only glibc
+    /// supports this, elsewhere it is the same as `place_new`.
+    pub fn place_new_prefer_writers(&mut self) {
+        self.init(true)
+    }
+
+    fn init(&mut self, prefer_writers: bool) {
+        unsafe {
+            let mut attr: libc::pthread_rwlockattr_t = mem::zeroed();
+            libc::pthread_rwlockattr_init(&mut attr);
+            libc::pthread_rwlockattr_setpshared(&mut attr, libc::PTHREAD_PROCESS_SHARED);
+            #[cfg(all(target_os = "linux", target_env = "gnu"))]
+            {
+                if prefer_writers {
+                    libc::pthread_rwlockattr_setkind_np(&mut attr, PTHREAD_RWLOCK_PREFER_WRITER_NONRECURSIVE_NP);
+                }
+            }
+            #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+            let _ = prefer_writers;
+            libc::pthread_rwlock_init(self.rwlock.get(), &attr);
+            libc::pthread_rwlockattr_destroy(&mut attr);
+        }
+    }
+
+    pub fn read(&self) -> SharedReadGuard<'_> {
+        unsafe { libc::pthread_rwlock_rdlock(self.rwlock.get()) };
+        SharedReadGuard(self)
+    }
+
+    pub fn try_read(&self) -> Option<SharedReadGuard<'_>> {
+        match unsafe { libc::pthread_rwlock_tryrdlock(self.rwlock.get()) } {
+            0 => Some(SharedReadGuard(self)),
+            _ => None,
+        }
+    }
+
+    pub fn write(&self) -> SharedWriteGuard<'_> {
+        unsafe { libc::pthread_rwlock_wrlock(self.rwlock.get()) };
+        SharedWriteGuard(self)
+    }
+
+    pub fn try_write(&self) -> Option<SharedWriteGuard<'_>> {
+        match unsafe { libc::pthread_rwlock_trywrlock(self.rwlock.get()) } {
+            0 => Some(SharedWriteGuard(self)),
+            _ => None,
+        }
+    }
+}
+
+/// Shared access to a `SharedRwLock`, released when dropped.
+pub struct SharedReadGuard<'a>(&'a SharedRwLock);
+
+impl<'a> Drop for SharedReadGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_rwlock_unlock(self.0.rwlock.get()) };
+    }
+}
+
+/// Exclusive access to a `SharedRwLock`, released when dropped.
+pub struct SharedWriteGuard<'a>(&'a SharedRwLock);
+
+impl<'a> Drop for SharedWriteGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_rwlock_unlock(self.0.rwlock.get()) };
+    }
+}
+
+#[test]
+fn test_condvar() {
+    use sync::lock_guard;
+    use mapped_region::anon_shared_memory;
+    use std::time::Instant;
+
+    #[repr(C)]
+    struct Shared {
+        mutex: SharedMutex,
+        filled: Condvar,
+        emptied: Condvar,
+        value: u32,
+    }
+
+    let region = anon_shared_memory(mem::size_of::<Shared>()).unwrap();
+    let shared = unsafe { &mut *(region.base() as *mut Shared) };
+    shared.mutex.place_new();
+    shared.filled.place_new();
+    shared.emptied.place_new();
+    shared.value = 0;
+    let value = &shared.value as *const u32;
+
+    let start = Instant::now();
+    let guard = lock_guard(&mut shared.mutex);
+    let (guard, timed_out) = shared.filled.wait_timeout(guard, Duration::from_millis(50));
+    assert!(timed_out);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    drop(guard);
+
+    // The child produces values, and waits until each one is taken.
+    match unsafe { libc::fork() } {
+        0 => {
+            for i in 1..4 {
+                let guard = lock_guard(&mut shared.mutex);
+                let guard = shared.emptied.wait_while(guard, || unsafe { *value } != 0);
+                shared.value = i;
+                shared.filled.notify_one();
+                drop(guard);
+            }
+            unsafe { libc::_exit(0) }
+        },
+        pid => {
+            let mut sum = 0;
+            for _ in 0..3 {
+                let guard = lock_guard(&mut shared.mutex);
+                let guard = shared.filled.wait_while(guard, || unsafe { *value } == 0);
+                sum += shared.value;
+                shared.value = 0;
+                shared.emptied.notify_all();
+                drop(guard);
+            }
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            assert_eq!(sum, 1 + 2 + 3);
+        },
+    }
+}
+
+#[test]
+fn test_robust_mutex() {
+    use mapped_region::anon_shared_memory;
+
+    fn die_holding(mutex: &mut RobustMutex) {
+        match unsafe { libc::fork() } {
+            0 => {
+                mem::forget(mutex.lock());
+                unsafe { libc::_exit(0) }
+            },
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            },
+        }
+    }
+
+    let region = anon_shared_memory(mem::size_of::<RobustMutex>()).unwrap();
+    let mutex = unsafe { &mut *(region.base() as *mut RobustMutex) };
+    mutex.place_new();
+    assert!(!mutex.lock().unwrap().owner_died());
+
+    die_holding(mutex);
+    match mutex.lock().unwrap() {
+        RobustLock::OwnerDied(mut guard) => guard.consistent().unwrap(),
+        RobustLock::Locked(_) => panic!("owner death not reported"),
+    }
+    assert!(!mutex.try_lock().unwrap().unwrap().owner_died());
+
+    die_holding(mutex);
+    let lock = mutex.lock().unwrap();
+    assert!(lock.owner_died());
+    lock.into_guard().mark_unrecoverable();
+    assert_eq!(mutex.lock().err().unwrap().raw_os_error(), Some(libc::ENOTRECOVERABLE));
+    assert!(mutex.try_lock().is_err());
+}
+
+#[test]
+fn test_shared_rwlock() {
+    use mapped_region::anon_shared_memory;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
+    use std::time::Instant;
+
+    #[repr(C)]
+    struct Shared {
+        lock: SharedRwLock,
+        stage: AtomicU32,
+    }
+
+    fn in_child<F: FnOnce() -> bool>(func: F) -> libc::pid_t {
+        match unsafe { libc::fork() } {
+            0 => unsafe { libc::_exit(if func() { 0 } else { 1 }) },
+            pid => pid,
+        }
+    }
+
+    fn succeeded(pid: libc::pid_t) -> bool {
+        let mut status = 0;
+        let waited = unsafe { libc::waitpid(pid, &mut status, 0) } == pid;
+        waited && libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+    }
+
+    fn wait_for(stage: &AtomicU32, value: u32) {
+        while stage.load(Ordering::SeqCst) != value {
+            thread::yield_now();
+        }
+    }
+
+    let region = anon_shared_memory(mem::size_of::<Shared>()).unwrap();
+    let shared = unsafe { &mut *(region.base() as *mut Shared) };
+    shared.lock.place_new();
+
+    // Children reach the lock through the address, while the parent holds it.
+    let addr = shared as *mut Shared as usize;
+    {
+        let _read = shared.lock.read();
+        assert!(succeeded(in_child(|| {
+            let lock = unsafe { &(*(addr as *const Shared)).lock };
+            let read = lock.try_read().is_some();
+            read && lock.try_write().is_none()
+        })));
+    }
+    {
+        let _write = shared.lock.write();
+        assert!(succeeded(in_child(|| {
+            let lock = unsafe { &(*(addr as *const Shared)).lock };
+            lock.try_read().is_none()
+        })));
+    }
+
+    // Threads of one process read at the same time.
+    {
+        let lock = &shared.lock;
+        let _read = lock.read();
+        assert!(thread::scope(|scope| scope.spawn(|| lock.try_read().is_some()).join().unwrap()));
+        assert!(lock.try_write().is_none());
+    }
+
+    // A reader in one child, and a writer waiting for it in another, keep
+    // new readers out.
+    shared.lock.place_new_prefer_writers();
+    shared.stage.store(0, Ordering::SeqCst);
+    let reader = in_child(|| {
+        let shared = unsafe { &mut *(addr as *mut Shared) };
+        let _read = shared.lock.read();
+        shared.stage.store(1, Ordering::SeqCst);
+        wait_for(&shared.stage, 3);
+        true
+    });
+    wait_for(&shared.stage, 1);
+    let writer = in_child(|| {
+        let shared = unsafe { &mut *(addr as *mut Shared) };
+        shared.stage.store(2, Ordering::SeqCst);
+        let _write = shared.lock.write();
+        true
+    });
+    wait_for(&shared.stage, 2);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut blocked = false;
+    while !blocked && Instant::now() < deadline {
+        blocked = shared.lock.try_read().is_none();
+        thread::yield_now();
+    }
+    shared.stage.store(3, Ordering::SeqCst);
+    assert!(succeeded(reader));
+    assert!(succeeded(writer));
+    if cfg!(all(target_os = "linux", target_env = "gnu")) {
+        assert!(blocked);
+    }
+    assert!(shared.lock.try_write().is_some());
+}
+
+#[test]
+fn test_shared_recursive_mutex() {
+    use mapped_region::anon_shared_memory;
+
+    fn try_lock_in_child(addr: usize) -> bool {
+        match unsafe { libc::fork() } {
+            0 => unsafe {
+                let mutex = &mut *(addr as *mut SharedRecursiveMutex);
+                libc::_exit(if mutex.try_lock() { 0 } else { 1 })
+            },
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            },
+        }
+    }
+
+    let region = anon_shared_memory(mem::size_of::<SharedRecursiveMutex>()).unwrap();
+    let mutex = unsafe { &mut *(region.base() as *mut SharedRecursiveMutex) };
+    let addr = mutex as *mut SharedRecursiveMutex as usize;
+    mutex.place_new();
+
+    mutex.lock();
+    mutex.lock();
+    assert!(mutex.try_lock());
+    assert!(!try_lock_in_child(addr));
+    mutex.unlock();
+    mutex.unlock();
+    assert!(!try_lock_in_child(addr));
+    mutex.unlock();
+    assert!(try_lock_in_child(addr));
+}
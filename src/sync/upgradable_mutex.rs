@@ -0,0 +1,234 @@
+use sync::{Mutex, SharedMutex, Condvar, lock_guard};
+use SharedType;
+use std::ptr;
+
+/// Mutex shared between processes with three kinds of ownership: any
+/// number of sharable owners, at most one of them upgradable, or a single
+/// exclusive owner. The upgradable owner can `upgrade` to exclusive ownership
+/// without letting another writer in first.
+///
+/// Exclusive ownership is taken through the `Mutex` trait.
+pub struct SharedUpgradableMutex {
+    mutex: SharedMutex,
+    /// Waited on by everybody until the exclusive or upgradable owner leaves.
+    first_gate: Condvar,
+    /// Waited on by a writer that has got in, until the sharable owners leave.
+    second_gate: Condvar,
+    exclusive_in: bool,
+    upgradable_in: bool,
+    /// Sharable owners, including the upgradable one.
+    num_shared: u32,
+}
+
+const MAX_SHARED: u32 = u32::MAX;
+
+/// Reads a field that other processes change while this one waits on a gate,
+/// so that it is not kept from before the wait.
+fn reread<T: Copy>(field: &T) -> T {
+    unsafe { ptr::read_volatile(field) }
+}
+
+impl SharedUpgradableMutex {
+    pub fn lock_sharable(&mut self) {
+        let mut guard = lock_guard(&mut self.mutex);
+        while reread(&self.exclusive_in) || reread(&self.num_shared) == MAX_SHARED {
+            guard = self.first_gate.wait(guard);
+        }
+        self.num_shared += 1;
+    }
+
+    pub fn try_lock_sharable(&mut self) -> bool {
+        let _guard = lock_guard(&mut self.mutex);
+        if self.exclusive_in || self.num_shared == MAX_SHARED {
+            return false
+        }
+        self.num_shared += 1;
+        true
+    }
+
+    pub fn unlock_sharable(&mut self) {
+        let _guard = lock_guard(&mut self.mutex);
+        self.num_shared -= 1;
+        if self.exclusive_in {
+            if self.num_shared == 0 {
+                self.second_gate.notify_one();
+            }
+        } else if self.num_shared == MAX_SHARED - 1 {
+            self.first_gate.notify_one();
+        }
+    }
+
+    /// Take sharable ownership that can later be upgraded. Waits while
+    /// another process owns the mutex exclusively or upgradably.
+    pub fn lock_upgradable(&mut self) {
+        let mut guard = lock_guard(&mut self.mutex);
+        while reread(&self.exclusive_in) || reread(&self.upgradable_in) || reread(&self.num_shared) == MAX_SHARED {
+            guard = self.first_gate.wait(guard);
+        }
+        self.upgradable_in = true;
+        self.num_shared += 1;
+    }
+
+    pub fn try_lock_upgradable(&mut self) -> bool {
+        let _guard = lock_guard(&mut self.mutex);
+        if self.exclusive_in || self.upgradable_in || self.num_shared == MAX_SHARED {
+            return false
+        }
+        self.upgradable_in = true;
+        self.num_shared += 1;
+        true
+    }
+
+    pub fn unlock_upgradable(&mut self) {
+        let _guard = lock_guard(&mut self.mutex);
+        self.upgradable_in = false;
+        self.num_shared -= 1;
+        self.first_gate.notify_all();
+    }
+
+    /// Turn upgradable ownership into exclusive ownership, once the other
+    /// sharable owners have left. New owners are kept out meanwhile.
+    pub fn upgrade(&mut self) {
+        let mut guard = lock_guard(&mut self.mutex);
+        self.upgradable_in = false;
+        self.num_shared -= 1;
+        self.exclusive_in = true;
+        while reread(&self.num_shared) != 0 {
+            guard = self.second_gate.wait(guard);
+        }
+    }
+
+    /// Turn exclusive ownership into upgradable ownership, letting
+    /// sharable owners in again.
+    pub fn downgrade(&mut self) {
+        let _guard = lock_guard(&mut self.mutex);
+        self.exclusive_in = false;
+        self.upgradable_in = true;
+        self.num_shared += 1;
+        self.first_gate.notify_all();
+    }
+}
+
+unsafe impl SharedType for SharedUpgradableMutex {
+    const NAME: &'static str = "SharedUpgradableMutex";
+}
+
+impl Mutex for SharedUpgradableMutex {
+    fn place_new(&mut self) {
+        self.mutex.place_new();
+        self.first_gate.place_new();
+        self.second_gate.place_new();
+        self.exclusive_in = false;
+        self.upgradable_in = false;
+        self.num_shared = 0;
+    }
+
+    fn lock(&mut self) {
+        let mut guard = lock_guard(&mut self.mutex);
+        while reread(&self.exclusive_in) || reread(&self.upgradable_in) {
+            guard = self.first_gate.wait(guard);
+        }
+        self.exclusive_in = true;
+        while reread(&self.num_shared) != 0 {
+            guard = self.second_gate.wait(guard);
+        }
+    }
+
+    fn try_lock(&mut self) -> bool {
+        let _guard = lock_guard(&mut self.mutex);
+        if self.exclusive_in || self.upgradable_in || self.num_shared != 0 {
+            return false
+        }
+        self.exclusive_in = true;
+        true
+    }
+
+    fn unlock(&mut self) {
+        let _guard = lock_guard(&mut self.mutex);
+        self.exclusive_in = false;
+        self.first_gate.notify_all();
+    }
+}
+
+#[test]
+fn test_upgradable_mutex() {
+    use mapped_region::anon_shared_memory;
+    use libc;
+    use std::mem;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[repr(C)]
+    struct Shared {
+        mutex: SharedUpgradableMutex,
+        stage: AtomicU32,
+        value: AtomicU32,
+    }
+
+    /// Run `func` on the mutex in a child process, and return what it returned.
+    fn in_child<F: FnOnce(&mut Shared) -> bool>(addr: usize, func: F) -> bool {
+        match unsafe { libc::fork() } {
+            0 => unsafe { libc::_exit(if func(&mut *(addr as *mut Shared)) { 0 } else { 1 }) },
+            pid => {
+                let mut status = 0;
+                let waited = unsafe { libc::waitpid(pid, &mut status, 0) } == pid;
+                waited && libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            },
+        }
+    }
+
+    let region = anon_shared_memory(mem::size_of::<Shared>()).unwrap();
+    let shared = unsafe { &mut *(region.base() as *mut Shared) };
+    shared.mutex.place_new();
+    let addr = shared as *mut Shared as usize;
+
+    shared.mutex.lock_upgradable();
+    assert!(in_child(addr, |shared| {
+        let sharable = shared.mutex.try_lock_sharable();
+        shared.mutex.unlock_sharable();
+        sharable && !shared.mutex.try_lock_upgradable() && !shared.mutex.try_lock()
+    }));
+
+    shared.mutex.upgrade();
+    assert!(in_child(addr, |shared| !shared.mutex.try_lock_sharable() && !shared.mutex.try_lock_upgradable()));
+
+    shared.mutex.downgrade();
+    assert!(in_child(addr, |shared| {
+        let sharable = shared.mutex.try_lock_sharable();
+        shared.mutex.unlock_sharable();
+        sharable && !shared.mutex.try_lock_upgradable()
+    }));
+    shared.mutex.unlock_upgradable();
+
+    // An upgrade waits for the sharable owner in the child to leave.
+    shared.stage.store(0, Ordering::SeqCst);
+    shared.value.store(0, Ordering::SeqCst);
+    let pid = match unsafe { libc::fork() } {
+        0 => unsafe {
+            let shared = &mut *(addr as *mut Shared);
+            shared.mutex.lock_sharable();
+            shared.stage.store(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            shared.value.store(1, Ordering::SeqCst);
+            shared.mutex.unlock_sharable();
+            libc::_exit(0)
+        },
+        pid => pid,
+    };
+    while shared.stage.load(Ordering::SeqCst) != 1 {
+        thread::yield_now();
+    }
+    shared.mutex.lock_upgradable();
+    shared.mutex.upgrade();
+    assert_eq!(shared.value.load(Ordering::SeqCst), 1);
+    shared.mutex.unlock();
+    assert_eq!(unsafe { libc::waitpid(pid, &mut 0, 0) }, pid);
+
+    assert!(shared.mutex.try_lock());
+    assert!(!shared.mutex.try_lock_sharable());
+    shared.mutex.unlock();
+    assert!(shared.mutex.try_lock_sharable());
+    assert!(!shared.mutex.try_lock());
+    shared.mutex.unlock_sharable();
+}